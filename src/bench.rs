@@ -4,9 +4,13 @@ extern crate test;
 mod tests {
     use super::test::{Bencher, black_box};
 
+    use crate::camera::*;
     use crate::linalg::*;
     use crate::draw::*;
+    use crate::light::*;
     use crate::resources::*;
+    use crate::shader::*;
+    use crate::tiles::*;
 
     #[bench]
     fn bench_draw_line(b: &mut Bencher) {
@@ -24,20 +28,16 @@ mod tests {
         });
     }
 
-    #[bench]
-    fn bench_draw_triangle_small_screen(b: &mut Bencher) {
-        const WIDTH: u32 = 32;
-        const HEIGHT: u32 = 32;
-        let mut screen = Screen::new(WIDTH as usize, HEIGHT as usize);
-
-        let cam_mat = Mat4x4f::translation(0.0, 0.0, -8.0);
-        let cam_inv = cam_mat.inverse();
+    // The same lit, textured cube for every screen size, drawn either directly or in tiles
+    fn bench_draw_cube(b: &mut Bencher, width: usize, height: usize, tiled: bool) {
+        let mut screen = Screen::new(width, height);
 
         let near: f32 = 0.1;
         let far: f32 = 1000.0;
         let fov: f32 = 80.0;
-        let aspect: f32 =  HEIGHT as f32 / WIDTH as f32;
-        let cam_proj = Mat4x4f::projection(near, far, aspect, fov);
+        let aspect: f32 =  height as f32 / width as f32;
+        let mut camera = Camera::perspective(fov, near, far, aspect);
+        camera.set_position(&Vec3f::new(0.0, 0.0, -8.0));
 
         let obj_mat = Mat4x4f::identity();
         
@@ -45,40 +45,72 @@ mod tests {
 
         let mesh = create_cube();
 
+        let vs = StandardVertexShader;
+        let lights = [Light::Directional { direction: Vec3f::new(0.0, -0.5, 1.0), color: Vec3f::new(1.0, 1.0, 1.0) }];
+        let fs = LitShader::new(&tex, &lights, &camera.position());
+        let state = RenderState::new();
+
         b.iter(|| {
             for _j in 1..10 {
-                draw_mesh(&mesh, &tex, &obj_mat, &cam_inv, &cam_proj, &mut screen);
+                if tiled {
+                    draw_mesh_tiled(&mesh, &obj_mat, &camera, &state, &vs, &fs, &mut screen);
+                } else {
+                    draw_mesh(&mesh, &obj_mat, &camera, &state, &vs, &fs, &mut screen);
+                }
                 black_box(0);
             }
         });
     }
 
+    #[bench]
+    fn bench_draw_triangle_small_screen(b: &mut Bencher) {
+        bench_draw_cube(b, 32, 32, false);
+    }
+
     #[bench]
     fn bench_draw_triangle_large_screen(b: &mut Bencher) {
-        const WIDTH: u32 = 400 * 4;
-        const HEIGHT: u32 = 300 * 4;
-        let mut screen = Screen::new(WIDTH as usize, HEIGHT as usize);
+        bench_draw_cube(b, 400 * 4, 300 * 4, false);
+    }
 
-        let cam_mat = Mat4x4f::translation(0.0, 0.0, -8.0);
-        let cam_inv = cam_mat.inverse();
+    #[bench]
+    fn bench_draw_triangle_large_screen_tiled(b: &mut Bencher) {
+        bench_draw_cube(b, 400 * 4, 300 * 4, true);
+    }
 
-        let near: f32 = 0.1;
-        let far: f32 = 1000.0;
-        let fov: f32 = 80.0;
-        let aspect: f32 =  HEIGHT as f32 / WIDTH as f32;
-        let cam_proj = Mat4x4f::projection(near, far, aspect, fov);
+    fn bench_process_geometry(b: &mut Bencher, mesh: &Mesh) {
+        let mut camera = Camera::perspective(80.0, 0.1, 1000.0, 0.75);
+        camera.set_position(&Vec3f::new(0.0, 0.0, -8.0));
 
         let obj_mat = Mat4x4f::identity();
-        
-        let tex = load_texture(String::from("resources/test.png")).unwrap();
-
-        let mesh = create_cube();
+        let vs = StandardVertexShader;
 
         b.iter(|| {
-            for _j in 1..10 {
-                draw_mesh(&mesh, &tex, &obj_mat, &cam_inv, &cam_proj, &mut screen);
-                black_box(0);
-            }
+            black_box(process_geometry(mesh, &obj_mat, &camera, &vs, 1600, 1200));
         });
     }
-}
\ No newline at end of file
+
+    // Each vertex is shared by up to 6 triangles, and gets shaded once
+    #[bench]
+    fn bench_process_geometry_high_poly(b: &mut Bencher) {
+        bench_process_geometry(b, &create_uv_sphere(3.0, 256, 128));
+    }
+
+    /*
+        The same mesh with every corner made a vertex of its own, so the
+        vertex shader runs once per corner, as it would without the vertex
+        cache. The difference with the above is what sharing buys us.
+    */
+    #[bench]
+    fn bench_process_geometry_high_poly_unshared(b: &mut Bencher) {
+        let mesh = create_uv_sphere(3.0, 256, 128);
+        let normals = mesh.normals.as_ref().unwrap();
+
+        let mut unshared = Mesh::new(
+            mesh.tris.iter().map(|v| mesh.verts[*v]).collect(),
+            (0..mesh.tris.len()).collect(),
+            mesh.tris.iter().map(|v| mesh.uvs[*v]).collect()).unwrap();
+        unshared.normals = Some(mesh.tris.iter().map(|v| normals[*v]).collect());
+
+        bench_process_geometry(b, &unshared);
+    }
+}
@@ -0,0 +1,56 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use ramjet_rasterizer::draw::*;
+use ramjet_rasterizer::resources::*;
+use ramjet_rasterizer::scene::*;
+
+/*
+    Headless renderer: draws the demo scene into our cpu-side screen buffer
+    without ever opening a window, and writes each frame out as a PNG.
+
+    Usage: headless [num_frames] [output_dir]
+*/
+
+fn main() {
+    if let Err(e) = render_frames() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn render_frames() -> Result<(), String> {
+    const WIDTH: usize = 400;
+    const HEIGHT: usize = 300;
+
+    let args: Vec<String> = env::args().collect();
+
+    let num_frames: u32 = match args.get(1) {
+        Some(arg) => arg.parse().map_err(|_| format!("Invalid frame count: {}", arg))?,
+        None => 60,
+    };
+
+    let output_dir = Path::new(args.get(2).map(|s| s.as_str()).unwrap_or("output"));
+    fs::create_dir_all(output_dir).map_err(|e| e.to_string())?;
+
+    let mut screen = Screen::new(WIDTH, HEIGHT);
+    screen.set_multisample(Multisample::X4);
+    let mut scene = Scene::new(WIDTH, HEIGHT)?;
+
+    // Step time the same way the SDL loop does, so frames line up
+    let mut time = 0.0;
+
+    for frame in 0..num_frames {
+        scene.render(&mut screen, time);
+
+        let path = output_dir.join(format!("frame_{:04}.png", frame));
+        save_png(&screen, &path)?;
+
+        time += 0.016;
+    }
+
+    println!("Wrote {} frames to {}", num_frames, output_dir.display());
+
+    Ok(())
+}
@@ -0,0 +1,260 @@
+/*
+    Camera
+
+    Holds where the camera is, which way it looks, and how it projects the
+    world onto the screen. The view and projection matrices are rebuilt
+    whenever one of those changes, so drawing only ever reads them.
+
+    Like the rest of the renderer, this is left-handed: the camera looks
+    down its local +z axis, with +y up and +x to the right.
+
+    Aspect is height / width, as Mat4x4f::projection expects it.
+
+    Screens drawn into with a camera should use the depth convention of its
+    projection, see Projection::depth_convention.
+*/
+
+use crate::draw::DepthConvention;
+use crate::linalg::*;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Projection {
+    // Vertical field of view in degrees
+    Perspective { fov: f32, near: f32, far: f32 },
+    // Perspective without a far plane, with reversed depth for precision out in the distance
+    PerspectiveReversedInfinite { fov: f32, near: f32 },
+    // Height of the view volume in world units, its width follows from the aspect
+    Orthographic { height: f32, near: f32, far: f32 },
+}
+
+impl Projection {
+    // The depth convention that screens drawn into with this projection need
+    pub fn depth_convention(&self) -> DepthConvention {
+        match self {
+            Projection::PerspectiveReversedInfinite { .. } => DepthConvention::Reversed,
+            _ => DepthConvention::Standard,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Camera {
+    position: Vec3f,
+    orientation: Mat4x4f,
+    projection: Projection,
+    aspect: f32,
+
+    view: Mat4x4f,
+    proj: Mat4x4f,
+}
+
+impl Camera {
+    pub fn new(projection: Projection, aspect: f32) -> Camera {
+        let mut cam = Camera {
+            position: Vec3f::new(0.0, 0.0, 0.0),
+            orientation: Mat4x4f::identity(),
+            projection: projection,
+            aspect: aspect,
+            view: Mat4x4f::identity(),
+            proj: Mat4x4f::identity(),
+        };
+
+        cam.update_view();
+        cam.update_projection();
+        cam
+    }
+
+    pub fn perspective(fov: f32, near: f32, far: f32, aspect: f32) -> Camera {
+        Camera::new(Projection::Perspective { fov: fov, near: near, far: far }, aspect)
+    }
+
+    pub fn perspective_reversed_infinite(fov: f32, near: f32, aspect: f32) -> Camera {
+        Camera::new(Projection::PerspectiveReversedInfinite { fov: fov, near: near }, aspect)
+    }
+
+    pub fn orthographic(height: f32, near: f32, far: f32, aspect: f32) -> Camera {
+        Camera::new(Projection::Orthographic { height: height, near: near, far: far }, aspect)
+    }
+
+    pub fn position(&self) -> Vec3f {
+        self.position
+    }
+
+    // Rotation from camera space to world space
+    pub fn orientation(&self) -> Mat4x4f {
+        self.orientation
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
+    // World to camera space
+    pub fn view(&self) -> &Mat4x4f {
+        &self.view
+    }
+
+    // Camera to clip space
+    pub fn proj(&self) -> &Mat4x4f {
+        &self.proj
+    }
+
+    pub fn right(&self) -> Vec3f {
+        Vec3f::from(&self.orientation[0])
+    }
+
+    pub fn up(&self) -> Vec3f {
+        Vec3f::from(&self.orientation[1])
+    }
+
+    pub fn forward(&self) -> Vec3f {
+        Vec3f::from(&self.orientation[2])
+    }
+
+    pub fn set_position(&mut self, position: &Vec3f) {
+        self.position = *position;
+        self.update_view();
+    }
+
+    pub fn set_orientation(&mut self, orientation: &Mat4x4f) {
+        self.orientation = *orientation;
+        self.update_view();
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+        self.update_projection();
+    }
+
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
+        self.update_projection();
+    }
+
+    /*
+        Turn to face target, keeping the camera's up as close to the given up
+        as we can. When looking straight along up, which way is right is
+        anyone's guess, so we go by the world z axis instead, or x if we're
+        looking along that too.
+    */
+    pub fn look_at(&mut self, target: &Vec3f, up: &Vec3f) {
+        let forward = (*target - self.position).normalize();
+
+        let mut right = Vec3f::cross(up, &forward);
+        if right.length() <= 0.00001 * up.length() {
+            let axis = if forward.z.abs() < 0.9 { Vec3f::new(0.0, 0.0, 1.0) } else { Vec3f::new(1.0, 0.0, 0.0) };
+            right = Vec3f::cross(&axis, &forward);
+        }
+
+        let right = right.normalize();
+        let up = Vec3f::cross(&forward, &right);
+
+        self.orientation = Mat4x4f::from_columns(&[
+            Vec4f::new(right.x, right.y, right.z, 0.0),
+            Vec4f::new(up.x, up.y, up.z, 0.0),
+            Vec4f::new(forward.x, forward.y, forward.z, 0.0),
+            Vec4f::new(0.0, 0.0, 0.0, 1.0),
+        ]);
+        self.update_view();
+    }
+
+    fn update_view(&mut self) {
+        // Orientation is a pure rotation, so its inverse is its transpose
+        let p = self.position;
+        self.view = self.orientation.transpose() * Mat4x4f::translation(-p.x, -p.y, -p.z);
+    }
+
+    fn update_projection(&mut self) {
+        self.proj = match self.projection {
+            Projection::Perspective { fov, near, far } => {
+                Mat4x4f::projection(near, far, self.aspect, fov)
+            },
+            Projection::PerspectiveReversedInfinite { fov, near } => {
+                Mat4x4f::projection_reversed_infinite(near, self.aspect, fov)
+            },
+            Projection::Orthographic { height, near, far } => {
+                let half_height = height * 0.5;
+                let half_width = half_height / self.aspect;
+                Mat4x4f::orthographic(-half_width, half_width, -half_height, half_height, near, far)
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::ApproxEq;
+
+    fn approx_eq(a: &Vec4f, b: &Vec4f) -> bool {
+        a.approx_eq(b, 0.00001, 4)
+    }
+
+    #[test]
+    fn test_view_matches_inverse_transform() {
+        // The way the demo used to set up its camera by hand
+        let mut cam = Camera::perspective(80.0, 0.1, 1000.0, 0.75);
+        cam.set_position(&Vec3f::new(0.0, 0.0, -8.0));
+
+        let expected = Mat4x4f::translation(0.0, 0.0, -8.0).inverse();
+        assert!(cam.view().approx_eq(&expected, 0.00001, 4));
+        assert_eq!(*cam.proj(), Mat4x4f::projection(0.1, 1000.0, 0.75, 80.0));
+    }
+
+    #[test]
+    fn test_look_at() {
+        let mut cam = Camera::perspective(80.0, 0.1, 1000.0, 0.75);
+        cam.set_position(&Vec3f::new(5.0, 3.0, -2.0));
+        cam.look_at(&Vec3f::new(1.0, 1.0, 2.0), &Vec3f::new(0.0, 1.0, 0.0));
+
+        // The camera sits at the origin of view space, with its target straight ahead
+        let view = *cam.view();
+        assert!(approx_eq(&(view * Vec4f::new(5.0, 3.0, -2.0, 1.0)), &Vec4f::new(0.0, 0.0, 0.0, 1.0)));
+        assert!(approx_eq(&(view * Vec4f::new(1.0, 1.0, 2.0, 1.0)), &Vec4f::new(0.0, 0.0, 6.0, 1.0)));
+
+        // Right stays level, up tilts towards world up
+        assert!(cam.right().y.abs() < 0.00001);
+        assert!(cam.up().y > 0.0);
+
+        // Looking the other way flips what's to our right
+        cam.look_at(&Vec3f::new(5.0, 3.0, -10.0), &Vec3f::new(0.0, 1.0, 0.0));
+        assert!(approx_eq(&(*cam.view() * Vec4f::new(6.0, 3.0, -2.0, 1.0)), &Vec4f::new(-1.0, 0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn test_look_at_along_up() {
+        let mut cam = Camera::perspective(80.0, 0.1, 1000.0, 0.75);
+        cam.set_position(&Vec3f::new(0.0, 10.0, 0.0));
+
+        // Straight down with y up, then straight up
+        for target in [Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(0.0, 20.0, 0.0)].iter() {
+            cam.look_at(target, &Vec3f::new(0.0, 1.0, 0.0));
+
+            let view = *cam.view();
+            assert!((0..4).all(|i| (0..4).all(|j| view[i][j].is_finite())));
+            assert!(approx_eq(&(view * Vec4f::new(target.x, target.y, target.z, 1.0)), &Vec4f::new(0.0, 0.0, 10.0, 1.0)));
+            assert!(Vec3f::dot(&cam.right(), &cam.up()).abs() < 0.00001);
+        }
+
+        // Along z, which the fallback axis can't be
+        cam.set_position(&Vec3f::new(0.0, 0.0, 0.0));
+        cam.look_at(&Vec3f::new(0.0, 0.0, 5.0), &Vec3f::new(0.0, 0.0, 1.0));
+        assert!(approx_eq(&(*cam.view() * Vec4f::new(0.0, 0.0, 5.0, 1.0)), &Vec4f::new(0.0, 0.0, 5.0, 1.0)));
+        assert!(cam.right().length() > 0.99);
+    }
+
+    #[test]
+    fn test_orthographic() {
+        let mut cam = Camera::orthographic(4.0, 1.0, 11.0, 0.5);
+        cam.set_position(&Vec3f::new(0.0, 0.0, -6.0));
+
+        // Corners of the view volume land on the corners of the visible clip space
+        let view_proj = *cam.proj() * *cam.view();
+        assert!(approx_eq(&(view_proj * Vec4f::new(-4.0, -2.0, -5.0, 1.0)), &Vec4f::new(-0.5, -0.5, 0.0, 1.0)));
+        assert!(approx_eq(&(view_proj * Vec4f::new(4.0, 2.0, 5.0, 1.0)), &Vec4f::new(0.5, 0.5, 1.0, 1.0)));
+    }
+}
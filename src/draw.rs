@@ -2,7 +2,6 @@
     Todo:
 
     - Features
-        - Clipping geometry to frustum
         - Anti-aliasing by subdividing edge pixels
         - Back-face culling by checking sign of cross product of edges
 
@@ -16,7 +15,6 @@
             - Test corners first. If all corners lie in triangle, inside of block does too
                 - large screen-space triangles benefit
             - Tiles with morton-order indexing could have better cache behaviour
-        - Fixed point arithmetic
 
 */
 
@@ -24,36 +22,490 @@
 
 extern crate float_cmp;
 
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::camera::*;
 use crate::linalg::*;
+use crate::shader::*;
+
+/*
+    Which way depth runs after projection.
+
+    Standard puts the near plane at 0 and the far plane at 1, which is what
+    Mat4x4f::projection and Mat4x4f::orthographic produce. Reversed puts the
+    near plane at 1 and the far plane at 0, which is what
+    Mat4x4f::projection_reversed_infinite produces. Floats are much denser
+    near 0, so reversing spends that precision on the distance, where
+    perspective had stretched depth out the most.
+
+    The camera's projection and the screen it draws into need to agree on this.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DepthConvention {
+    Standard,
+    Reversed,
+}
+
+impl DepthConvention {
+    // The depth of the far plane, that nothing can lie behind
+    pub fn clear_value(&self) -> f32 {
+        match self {
+            DepthConvention::Standard => 1.0,
+            DepthConvention::Reversed => 0.0,
+        }
+    }
+
+    // Whether depth a is closer to the camera than depth b
+    pub fn closer(&self, a: f32, b: f32) -> bool {
+        match self {
+            DepthConvention::Standard => a < b,
+            DepthConvention::Reversed => a > b,
+        }
+    }
+}
+
+/*
+    Depth test comparisons, of an incoming fragment's depth against what's
+    in the buffer.
+
+    These are in terms of distance from the camera rather than raw depth
+    values, so the same state works with either depth convention: Less
+    always lets through fragments that are closer than what's there.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DepthFunc {
+    Never,
+    Less,
+    LessEqual,
+    Equal,
+    Greater,
+    Always,
+}
+
+impl DepthFunc {
+    pub fn test(&self, convention: DepthConvention, depth: f32, stored: f32) -> bool {
+        match self {
+            DepthFunc::Never => false,
+            DepthFunc::Less => convention.closer(depth, stored),
+            DepthFunc::LessEqual => depth == stored || convention.closer(depth, stored),
+            DepthFunc::Equal => depth == stored,
+            DepthFunc::Greater => convention.closer(stored, depth),
+            DepthFunc::Always => true,
+        }
+    }
+}
+
+/*
+    Fixed-function state for a draw call, for the parts of the pipeline
+    that shaders don't control.
+
+    Depth test and depth write are independent: with the test off, every
+    fragment passes, and with writing on, each one still lands in the depth
+    buffer. Turn both off for overlays that shouldn't affect anything else.
+
+    Turning off color writes leaves only depth, for depth-only passes such
+    as rendering shadow maps.
+
+    Alpha test discards fragments with an alpha below the threshold, in
+    [0,1], before they get blended or write depth. That gives cutouts like
+    foliage and fences hard edges that still depth sort correctly.
+*/
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct RenderState {
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_func: DepthFunc,
+    pub color_write: bool,
+    pub blend: BlendMode,
+    pub alpha_test: Option<f32>,
+}
+
+impl Default for RenderState {
+    fn default() -> RenderState {
+        RenderState::new()
+    }
+}
+
+impl RenderState {
+    // Regular opaque geometry: nearest surface wins
+    pub fn new() -> RenderState {
+        RenderState {
+            depth_test: true,
+            depth_write: true,
+            depth_func: DepthFunc::Less,
+            color_write: true,
+            blend: BlendMode::Opaque,
+            alpha_test: None,
+        }
+    }
+
+    // Alpha blended, depth tested but not written, so it doesn't hide what's behind it.
+    // Draw these after opaque geometry, back to front.
+    pub fn transparent() -> RenderState {
+        RenderState {
+            depth_write: false,
+            blend: BlendMode::Alpha,
+            ..RenderState::new()
+        }
+    }
+
+    pub fn depth_only() -> RenderState {
+        RenderState {
+            color_write: false,
+            ..RenderState::new()
+        }
+    }
+}
+
+/*
+    How translucent fragments, those with a blend mode other than Opaque,
+    make it onto the screen.
+
+    InDrawOrder blends each one in as soon as it's shaded. That is only
+    right if they come in back to front, which sorting by object can't
+    guarantee once objects intersect.
+
+    OrderIndependent keeps an A-buffer instead: a list of fragments per
+    pixel, that resolve_transparency sorts by depth and blends back to
+    front once the frame is done. Correct regardless of draw order, at the
+    cost of memory that grows with the number of layers. Translucent
+    fragments never write depth in this mode. Screen::set_transparency
+    allocates the A-buffer, so screens that don't need it don't pay for it.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TransparencyMode {
+    InDrawOrder,
+    OrderIndependent,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct TransparentFragment {
+    pub depth: f32,
+    pub color: Color,
+    pub blend: BlendMode,
+    pub depth_func: DepthFunc, // Tested again against the opaque depth when resolving
+    pub coverage: u32, // Which of the pixel's samples it covers, see Multisample
+}
+
+/*
+    Multisample anti-aliasing
+
+    Coverage and depth get decided at several sample points within each
+    pixel, rather than just at its center, and each sample keeps a color
+    and depth of its own. The fragment shader still only runs once per
+    pixel that a triangle touches, and its color goes to all the samples
+    the triangle covers and that pass the depth test. So edges get smooth,
+    at the cost of memory but not of much shading.
+
+    Drawing goes into the screen's sample buffers, and resolve_samples
+    then averages each pixel's samples into its color buffer. Do that once
+    the frame is done, before showing it or saving it.
+
+    Sample positions follow the standard D3D patterns, which are spread out
+    so that near horizontal and near vertical edges both get a good number
+    of distinct steps.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Multisample {
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+pub const MAX_SAMPLES: usize = 8;
+
+impl Multisample {
+    pub fn samples(&self) -> usize {
+        self.pattern().len()
+    }
+
+    // Sample offsets from the pixel center, in 16ths of a pixel, with y pointing down
+    pub fn pattern(&self) -> &'static [(i32, i32)] {
+        match self {
+            Multisample::Off => &[(0, 0)],
+            Multisample::X2 => &[(4, 4), (-4, -4)],
+            Multisample::X4 => &[(-2, -6), (6, -2), (-6, 2), (2, 6)],
+            Multisample::X8 => &[(1, -3), (-1, 3), (5, 1), (-3, -5), (-5, 5), (-7, -1), (3, 7), (7, -7)],
+        }
+    }
+}
 
 pub struct Screen {
-    pub color: Vec<u8>,
+    pub color: Vec<u8>, // Empty for depth_only screens
     pub depth: Vec<f32>,
+    pub fragments: Vec<Vec<TransparentFragment>>, // Per pixel, for OrderIndependent transparency. Empty otherwise.
     pub width: usize,
     pub height: usize,
+    pub depth_convention: DepthConvention,
+    pub transparency: TransparencyMode, // Change with set_transparency
+    pub multisample: Multisample,  // Change with set_multisample
+    pub sample_color: Vec<u8>,     // RGB, for each sample of each pixel in turn. Empty without multisampling.
+    pub sample_depth: Vec<f32>,    // Likewise
 }
 
 impl Screen {
     pub fn new(width: usize, height: usize) -> Screen {
-        let color_buffer_size = width * height * 3;
-        let depth_buffer_size = width * height;
+        Screen::with_depth_convention(width, height, DepthConvention::Standard)
+    }
+
+    /*
+        Just a depth buffer, for passes that only ever draw with color
+        writes off, like rendering shadow maps. Drawing color into it panics.
+    */
+    pub fn depth_only(width: usize, height: usize) -> Screen {
+        Screen::with_buffers(width, height, DepthConvention::Standard, Vec::new())
+    }
 
-        let color_buffer: Vec<u8> = vec![0; color_buffer_size];
-        let depth_buffer: Vec<f32> = vec![1000.0; depth_buffer_size];
+    pub fn with_depth_convention(width: usize, height: usize, depth_convention: DepthConvention) -> Screen {
+        let color_buffer: Vec<u8> = vec![0; width * height * 3];
+        Screen::with_buffers(width, height, depth_convention, color_buffer)
+    }
+
+    fn with_buffers(width: usize, height: usize, depth_convention: DepthConvention, color_buffer: Vec<u8>) -> Screen {
+        assert!(width > 0 && height > 0, "screen must be at least 1x1, got {}x{}", width, height);
+        let depth_buffer: Vec<f32> = vec![depth_convention.clear_value(); width * height];
 
         Screen {
             color: color_buffer,
             depth: depth_buffer,
+            fragments: Vec::new(),
             width: width,
             height: height,
+            depth_convention: depth_convention,
+            transparency: TransparencyMode::InDrawOrder,
+            multisample: Multisample::Off,
+            sample_color: Vec::new(),
+            sample_depth: Vec::new(),
+        }
+    }
+
+    // Sets up the A-buffer, empty, or frees it when going back to drawing in order
+    pub fn set_transparency(&mut self, transparency: TransparencyMode) {
+        let num_pixels = if transparency == TransparencyMode::OrderIndependent { self.width * self.height } else { 0 };
+
+        self.transparency = transparency;
+        self.fragments = vec![Vec::new(); num_pixels];
+    }
+
+    // Sets up the sample buffers, cleared, or frees them when turning multisampling off
+    pub fn set_multisample(&mut self, multisample: Multisample) {
+        let num_samples = if multisample == Multisample::Off { 0 } else { self.width * self.height * multisample.samples() };
+
+        self.multisample = multisample;
+        self.sample_color = vec![0; num_samples * 3];
+        self.sample_depth = vec![self.depth_convention.clear_value(); num_samples];
+    }
+
+    /*
+        The buffers drawing goes into: the sample buffers when multisampling,
+        or else the color and depth buffers themselves, as a single sample
+        per pixel.
+    */
+    fn sample_buffers(&mut self) -> (&mut [u8], &mut [f32], &mut [Vec<TransparentFragment>]) {
+        if self.multisample == Multisample::Off {
+            (&mut self.color, &mut self.depth, &mut self.fragments)
+        } else {
+            (&mut self.sample_color, &mut self.sample_depth, &mut self.fragments)
         }
     }
+
+    // The whole screen as a single render target
+    pub fn target(&mut self) -> RenderTarget<'_> {
+        let size = usize::max(self.width, self.height);
+        self.tiles(size).remove(0)
+    }
+
+    /*
+        Splits the screen into square tiles of the given size, row by row,
+        each of which can be drawn into independently. Tiles on the right
+        and bottom edges get cut short by the screen's.
+    */
+    pub fn tiles(&mut self, size: usize) -> Vec<RenderTarget<'_>> {
+        assert!(size > 0, "tile size must be at least 1");
+        let (width, height) = (self.width, self.height);
+        let depth_convention = self.depth_convention;
+        let transparency = self.transparency;
+        let multisample = self.multisample;
+        let samples = multisample.samples();
+        let tiles_x = width.div_ceil(size);
+        let tiles_y = height.div_ceil(size);
+
+        let mut tiles: Vec<RenderTarget> = (0..tiles_x * tiles_y).map(|i| {
+            let (tile_x, tile_y) = (i % tiles_x, i / tiles_x);
+
+            RenderTarget {
+                width: width,
+                height: height,
+                x_min: tile_x * size,
+                x_max: usize::min((tile_x + 1) * size, width),
+                y_min: tile_y * size,
+                y_max: usize::min((tile_y + 1) * size, height),
+                color: Vec::with_capacity(size),
+                depth: Vec::with_capacity(size),
+                fragments: Vec::with_capacity(size),
+                depth_convention: depth_convention,
+                transparency: transparency,
+                multisample: multisample,
+            }
+        }).collect();
+
+        // Buffers that aren't in use are empty, and hand out no rows at all
+        let (color, depth, fragments) = self.sample_buffers();
+        split_into_tiles(color, width, samples * 3, size, |i, row| tiles[i].color.push(row));
+        split_into_tiles(depth, width, samples, size, |i, row| tiles[i].depth.push(row));
+        split_into_tiles(fragments, width, 1, size, |i, row| tiles[i].fragments.push(row));
+
+        tiles
+    }
+}
+
+/*
+    Cuts a screen buffer, holding stride values for each pixel, into rows,
+    and those into a tile's width at a time, handing each piece to the tile
+    it belongs to by index.
+*/
+fn split_into_tiles<'a, T, F>(buffer: &'a mut [T], width: usize, stride: usize, size: usize, mut add: F)
+    where F: FnMut(usize, &'a mut [T]) {
+    let tiles_x = width.div_ceil(size);
+
+    for (y, row) in buffer.chunks_mut(width * stride).enumerate() {
+        for (tile_x, piece) in row.chunks_mut(size * stride).enumerate() {
+            add((y / size) * tiles_x + tile_x, piece);
+        }
+    }
+}
+
+/*
+    A view onto the pixels [x_min, x_max) x [y_min, y_max) of a screen,
+    which is what the rasterizer draws into. Pixel coordinates are still
+    those of the full screen.
+
+    The buffers are held a row at a time, each a slice of that row of the
+    screen's, so that tiles side by side don't overlap. Each pixel has a
+    run of samples, which is just the one without multisampling, see
+    Screen::sample_buffers.
+*/
+pub struct RenderTarget<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub x_min: usize,
+    pub x_max: usize,
+    pub y_min: usize,
+    pub y_max: usize,
+    pub color: Vec<&'a mut [u8]>,
+    pub depth: Vec<&'a mut [f32]>,
+    pub fragments: Vec<&'a mut [Vec<TransparentFragment>]>, // No rows unless transparency is OrderIndependent
+    pub depth_convention: DepthConvention,
+    pub transparency: TransparencyMode,
+    pub multisample: Multisample,
+}
+
+impl<'a> RenderTarget<'a> {
+    // The row, and the index of the sample within it
+    fn sample_index(&self, x: usize, y: usize, sample: usize) -> (usize, usize) {
+        assert!(x >= self.x_min && x < self.x_max);
+        assert!(y >= self.y_min && y < self.y_max);
+
+        let samples = self.multisample.samples();
+        assert!(sample < samples);
+
+        (y - self.y_min, (x - self.x_min) * samples + sample)
+    }
+
+    pub fn set_color(&mut self, x: usize, y: usize, sample: usize, c: &Color) {
+        let (row, index) = self.sample_index(x, y, sample);
+        let color = &mut self.color[row][index * 3..index * 3 + 3];
+
+        color[0] = c.r;
+        color[1] = c.g;
+        color[2] = c.b;
+    }
+
+    pub fn set_depth(&mut self, x: usize, y: usize, sample: usize, d: f32) {
+        let (row, index) = self.sample_index(x, y, sample);
+        self.depth[row][index] = d;
+    }
+
+    pub fn get_color(&self, x: usize, y: usize, sample: usize) -> Color {
+        let (row, index) = self.sample_index(x, y, sample);
+        let color = &self.color[row][index * 3..index * 3 + 3];
+
+        Color::new(color[0], color[1], color[2])
+    }
+
+    pub fn get_depth(&self, x: usize, y: usize, sample: usize) -> f32 {
+        let (row, index) = self.sample_index(x, y, sample);
+        self.depth[row][index]
+    }
+
+    // Whether fragments drawn with this state go to the A-buffer
+    pub fn defers(&self, state: &RenderState) -> bool {
+        state.blend != BlendMode::Opaque && self.transparency == TransparencyMode::OrderIndependent
+    }
+
+    /*
+        The output stage: alpha test, then blending, then the writes. The
+        depth test has been done by now, before running the fragment shader,
+        and coverage has the samples that passed it. Each of those gets the
+        fragment's color, and its depth there.
+
+        With order-independent transparency, translucent fragments are put
+        aside in the A-buffer instead, to be blended in later.
+    */
+    pub fn write_fragment(&mut self, x: usize, y: usize, depth: f32, coverage: &Coverage, color: &Color, state: &RenderState) {
+        if let Some(threshold) = state.alpha_test {
+            if (color.a as f32 / 255.0) < threshold {
+                return;
+            }
+        }
+
+        if self.defers(state) {
+            self.fragments[y - self.y_min][x - self.x_min].push(TransparentFragment {
+                depth: depth,
+                color: *color,
+                blend: state.blend,
+                depth_func: if state.depth_test { state.depth_func } else { DepthFunc::Always },
+                coverage: coverage.mask,
+            });
+            return;
+        }
+
+        for sample in (0..self.multisample.samples()).filter(|s| coverage.covers(*s)) {
+            if state.color_write {
+                let color = match state.blend {
+                    BlendMode::Opaque => *color,
+                    blend => blend.blend(color, &self.get_color(x, y, sample)),
+                };
+
+                self.set_color(x, y, sample, &color);
+            }
+
+            if state.depth_write {
+                self.set_depth(x, y, sample, coverage.depths[sample]);
+            }
+        }
+    }
+}
+
+// The samples of a pixel that a triangle covers, as bits, along with its depth at each
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Coverage {
+    pub mask: u32,
+    pub depths: [f32; MAX_SAMPLES],
+}
+
+impl Coverage {
+    pub fn covers(&self, sample: usize) -> bool {
+        self.mask & (1 << sample) != 0
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
 pub struct Vec2i {
-    x: i32,
-    y: i32
+    pub x: i32,
+    pub y: i32
 }
 
 impl Vec2i {
@@ -65,22 +517,34 @@ impl Vec2i {
     }
 }
 
+// RGBA, with alpha as opacity: 0 is fully transparent, 255 fully opaque
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    pub a: u8,
 }
 
 impl Color {
+    // Opaque
     pub fn new(r: u8, g: u8, b: u8) -> Color {
+        Color::rgba(r, g, b, 255)
+    }
+
+    pub fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
         Color {
             r: r,
             g: g,
             b: b,
+            a: a,
         }
     }
 
+    pub fn transparent() -> Color {
+        Color::rgba(0, 0, 0, 0)
+    }
+
     pub fn white() -> Color {
         Color::new(255, 255, 255)
     }
@@ -101,52 +565,577 @@ impl Color {
         Color::new(0, 0, 255)
     }
 
+    // From floating point channels in [0,1], clamping anything outside of that. Opaque.
+    pub fn from_vec3(v: &Vec3f) -> Color {
+        Color::from_vec4(&Vec4f::new(v.x, v.y, v.z, 1.0))
+    }
+
+    pub fn to_vec3(&self) -> Vec3f {
+        Vec3f::new(self.r as f32 / 255.0, self.g as f32 / 255.0, self.b as f32 / 255.0)
+    }
+
+    // As from_vec3, with alpha in w
+    pub fn from_vec4(v: &Vec4f) -> Color {
+        let to_u8 = |c: f32| (c.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+        Color::rgba(to_u8(v.x), to_u8(v.y), to_u8(v.z), to_u8(v.w))
+    }
+
+    pub fn to_vec4(&self) -> Vec4f {
+        Vec4f::new(self.r as f32 / 255.0, self.g as f32 / 255.0, self.b as f32 / 255.0, self.a as f32 / 255.0)
+    }
+
+    // Same color, with its alpha replaced
+    pub fn with_alpha(&self, a: u8) -> Color {
+        Color::rgba(self.r, self.g, self.b, a)
+    }
+
+    // Linear interpolation between two colors, rounded to nearest
+    pub fn lerp(a: Color, b: Color, t: f32) -> Color {
+        let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t + 0.5) as u8;
+        Color::rgba(lerp(a.r, b.r), lerp(a.g, b.g), lerp(a.b, b.b), lerp(a.a, b.a))
+    }
+
     // Blend 3 colors using barycentric coordinates
     pub fn blend(a: Color, b: Color, c: Color, w0: f32, w1: f32, w2: f32) -> Color {
-        Color::new(
-            ((a.r as f32 * w0) + (b.r as f32 * w1) + (c.r as f32 * w2)) as u8,
-            ((a.g as f32 * w0) + (b.g as f32 * w1) + (c.g as f32 * w2)) as u8,
-            ((a.b as f32 * w0) + (b.b as f32 * w1) + (c.b as f32 * w2)) as u8,
-        )
+        let blend = |a: u8, b: u8, c: u8| ((a as f32 * w0) + (b as f32 * w1) + (c as f32 * w2)) as u8;
+        Color::rgba(blend(a.r, b.r, c.r), blend(a.g, b.g, c.g), blend(a.b, b.b, c.b), blend(a.a, b.a, c.a))
+    }
+}
+
+// Product of two 8-bit channels, treating 255 as 1 and rounding to nearest
+fn mul_u8(a: u8, b: u8) -> u8 {
+    ((a as u32 * b as u32 + 127) / 255) as u8
+}
+
+/*
+    How a fragment's color combines with what's already on screen. The
+    screen itself has no alpha, so all of these go by the fragment's.
+
+    - Opaque: replaces what's there, alpha is ignored
+    - Alpha: classic transparency, src * a + dst * (1 - a)
+    - Additive: adds light, for glows and particles, dst + src * a
+    - Multiplicative: tints what's behind, for stained glass and
+      shadow decals. Fades to no effect as alpha goes to 0.
+    - Premultiplied: like Alpha, for colors that have already been
+      multiplied by their alpha, src + dst * (1 - a)
+
+    Results are clamped to [0, 255].
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BlendMode {
+    Opaque,
+    Alpha,
+    Additive,
+    Multiplicative,
+    Premultiplied,
+}
+
+impl BlendMode {
+    pub fn blend(&self, src: &Color, dst: &Color) -> Color {
+        let a = src.a;
+        let inv_a = 255 - src.a;
+
+        let channel = |s: u8, d: u8| -> u8 {
+            match self {
+                BlendMode::Opaque => s,
+                BlendMode::Alpha => ((s as u32 * a as u32 + d as u32 * inv_a as u32 + 127) / 255) as u8,
+                BlendMode::Additive => d.saturating_add(mul_u8(s, a)),
+                BlendMode::Multiplicative => mul_u8(d, mul_u8(s, a) + inv_a),
+                BlendMode::Premultiplied => s.saturating_add(mul_u8(d, inv_a)),
+            }
+        };
+
+        Color::new(channel(src.r, dst.r), channel(src.g, dst.g), channel(src.b, dst.b))
     }
 }
 
+// A run of consecutive triangles that share a material
+#[derive(Debug, PartialEq, Clone)]
+pub struct SubMesh {
+    pub material: String,
+    pub first_tri: usize,
+    pub num_tris: usize,
+}
+
+/*
+    Vertex attributes are kept as parallel streams, each with one entry per
+    vertex, and tris indexes all of them at once. A vertex is the whole set
+    of its attributes, so where faces with different uvs or normals meet,
+    like at the corners of a cube, there are several vertices that share a
+    position.
+*/
+#[derive(Debug, Clone)]
 pub struct Mesh {
     pub verts: Vec<Vec4f>,
     pub tris: Vec<usize>,
     pub uvs: Vec<Vec2f>,
+    pub normals: Option<Vec<Vec3f>>, // Flat shaded if None
+    pub colors: Option<Vec<Color>>,
+    pub tangents: Option<Vec<Vec4f>>, // See compute_tangents
+    pub material_libs: Vec<String>, // Material library files the mesh refers to
+    pub submeshes: Vec<SubMesh>,
 }
 
 impl Mesh {
-    pub fn new(verts: Vec<Vec4f>, tris: Vec<usize>, uvs: Vec<Vec2f>) -> Mesh {
-        Mesh {
+    pub fn new(verts: Vec<Vec4f>, tris: Vec<usize>, uvs: Vec<Vec2f>) -> Result<Mesh, String> {
+        let mesh = Mesh {
             verts: verts,
             tris: tris,
             uvs: uvs,
+            normals: None,
+            colors: None,
+            tangents: None,
+            material_libs: Vec::new(),
+            submeshes: Vec::new(),
+        };
+
+        mesh.validate()?;
+        Ok(mesh)
+    }
+
+    /*
+        For data that indexes positions but has its uvs per triangle corner.
+        Every corner becomes a vertex of its own, and the ones that turn out
+        the same get welded back together.
+    */
+    pub fn from_corner_uvs(verts: Vec<Vec4f>, tris: Vec<usize>, uvs: Vec<Vec2f>) -> Result<Mesh, String> {
+        if uvs.len() != tris.len() {
+            return Err(format!("{} uvs for {} triangle corners", uvs.len(), tris.len()));
+        }
+
+        if let Some(v) = tris.iter().find(|v| **v >= verts.len()) {
+            return Err(format!("Vertex index {} out of range, there are {} vertices", v, verts.len()));
+        }
+
+        let corner_verts = tris.iter().map(|v| verts[*v]).collect();
+        let mut mesh = Mesh::new(corner_verts, (0..tris.len()).collect(), uvs)?;
+        mesh.weld();
+        Ok(mesh)
+    }
+
+    /*
+        Checks that the indices make up whole triangles and are all in range,
+        and that every attribute stream has one entry per vertex. Mesh::new
+        already does this, but it's worth doing again after filling in the
+        optional streams by hand.
+    */
+    pub fn validate(&self) -> Result<(), String> {
+        let num_verts = self.verts.len();
+
+        if !self.tris.len().is_multiple_of(3) {
+            return Err(format!("{} indices don't make whole triangles", self.tris.len()));
+        }
+
+        if let Some(i) = self.tris.iter().position(|v| *v >= num_verts) {
+            return Err(format!("Index {} at {} out of range, there are {} vertices", self.tris[i], i, num_verts));
+        }
+
+        let check_len = |name: &str, len: Option<usize>| match len {
+            Some(len) if len != num_verts => Err(format!("{} {} for {} vertices", len, name, num_verts)),
+            _ => Ok(()),
+        };
+
+        check_len("uvs", Some(self.uvs.len()))?;
+        check_len("normals", self.normals.as_ref().map(|n| n.len()))?;
+        check_len("colors", self.colors.as_ref().map(|c| c.len()))?;
+        check_len("tangents", self.tangents.as_ref().map(|t| t.len()))?;
+
+        let num_tris = self.tris.len() / 3;
+        if let Some(s) = self.submeshes.iter().find(|s| s.first_tri + s.num_tris > num_tris) {
+            return Err(format!("Submesh '{}' runs past the last of {} triangles", s.material, num_tris));
         }
+
+        Ok(())
+    }
+
+    /*
+        Merges vertices whose attributes are all exactly the same, and drops
+        the ones no triangle uses. Vertices keep the order in which the
+        triangles first use them. Returns how many vertices went away.
+    */
+    pub fn weld(&mut self) -> usize {
+        let num_verts = self.verts.len();
+
+        let key = |v: usize| {
+            let mut key: Vec<u32> = Vec::with_capacity(14);
+            let p = &self.verts[v];
+            key.extend_from_slice(&[p.x.to_bits(), p.y.to_bits(), p.z.to_bits(), p.w.to_bits()]);
+            key.extend_from_slice(&[self.uvs[v].x.to_bits(), self.uvs[v].y.to_bits()]);
+            if let Some(n) = self.normals.as_ref().map(|n| n[v]) {
+                key.extend_from_slice(&[n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]);
+            }
+            if let Some(c) = self.colors.as_ref().map(|c| c[v]) {
+                key.push(u32::from_le_bytes([c.r, c.g, c.b, c.a]));
+            }
+            if let Some(t) = self.tangents.as_ref().map(|t| t[v]) {
+                key.extend_from_slice(&[t.x.to_bits(), t.y.to_bits(), t.z.to_bits(), t.w.to_bits()]);
+            }
+            key
+        };
+
+        let mut ids: HashMap<Vec<u32>, usize> = HashMap::new();
+        let mut sources: Vec<usize> = Vec::new();
+
+        let tris = self.tris.iter().map(|v| {
+            *ids.entry(key(*v)).or_insert_with(|| {
+                sources.push(*v);
+                sources.len() - 1
+            })
+        }).collect();
+
+        self.select_vertices(&sources);
+        self.tris = tris;
+
+        num_verts - self.verts.len()
+    }
+
+    // Rebuilds every attribute stream from the given vertices, in order. Leaves tris alone.
+    fn select_vertices(&mut self, sources: &[usize]) {
+        fn select<T: Copy>(stream: &[T], sources: &[usize]) -> Vec<T> {
+            sources.iter().map(|v| stream[*v]).collect()
+        }
+
+        self.verts = select(&self.verts, sources);
+        self.uvs = select(&self.uvs, sources);
+        self.normals = self.normals.as_ref().map(|n| select(n, sources));
+        self.colors = self.colors.as_ref().map(|c| select(c, sources));
+        self.tangents = self.tangents.as_ref().map(|t| select(t, sources));
+    }
+
+    fn face_normal(&self, tri: usize) -> Vec3f {
+        let p1 = Vec3f::from(&self.verts[self.tris[tri*3 + 0]]);
+        let p2 = Vec3f::from(&self.verts[self.tris[tri*3 + 1]]);
+        let p3 = Vec3f::from(&self.verts[self.tris[tri*3 + 2]]);
+
+        Vec3f::cross(&(p2 - p1), &(p3 - p1)).normalize()
+    }
+
+    // Angle of a triangle at one of its corners, in radians
+    fn corner_angle(&self, tri: usize, corner: usize) -> f32 {
+        let p = Vec3f::from(&self.verts[self.tris[tri*3 + corner]]);
+        let a = Vec3f::from(&self.verts[self.tris[tri*3 + (corner + 1) % 3]]);
+        let b = Vec3f::from(&self.verts[self.tris[tri*3 + (corner + 2) % 3]]);
+
+        let cos = Vec3f::dot(&(a - p).normalize(), &(b - p).normalize());
+        f32::acos(cos.clamp(-1.0, 1.0))
+    }
+
+    /*
+        Generates smooth per-vertex normals by averaging the normals of the
+        faces around each vertex, weighted by the angle each face makes at
+        that vertex. (Weighting by angle rather than area means it doesn't
+        matter how a surface happens to be split up into triangles.)
+
+        Faces are gathered by position, so that vertices that only differ in
+        their other attributes, like on either side of a uv seam, still get
+        smoothed together.
+
+        Faces only get smoothed together if the angle between them is within
+        crease_angle (in degrees). Where that splits up the faces around a
+        vertex, the vertex gets duplicated so each side keeps its own normal,
+        along with the rest of its attributes.
+    */
+    pub fn compute_normals(&mut self, crease_angle: f32) {
+        let num_tris = self.tris.len() / 3;
+        let cos_crease = f32::cos(crease_angle / 180.0 * std::f32::consts::PI);
+
+        let face_normals: Vec<Vec3f> = (0..num_tris).map(|t| self.face_normal(t)).collect();
+
+        // Number the distinct positions
+        let mut position_ids: HashMap<[u32; 4], usize> = HashMap::new();
+        let vert_positions: Vec<usize> = self.verts.iter().map(|p| {
+            let next = position_ids.len();
+            *position_ids.entry([p.x.to_bits(), p.y.to_bits(), p.z.to_bits(), p.w.to_bits()]).or_insert(next)
+        }).collect();
+
+        // Which face corners touch each position, weighted by their angle
+        let mut position_faces: Vec<Vec<(usize, f32)>> = vec![Vec::new(); position_ids.len()];
+        for (i, v) in self.tris.iter().enumerate() {
+            position_faces[vert_positions[*v]].push((i / 3, self.corner_angle(i / 3, i % 3)));
+        }
+
+        let mut sources: Vec<usize> = Vec::with_capacity(self.verts.len());
+        let mut normals: Vec<Vec3f> = Vec::with_capacity(self.verts.len());
+        let mut tris: Vec<usize> = Vec::with_capacity(self.tris.len());
+
+        // For each original vertex, the new vertices we split it into
+        let mut splits: Vec<Vec<usize>> = vec![Vec::new(); self.verts.len()];
+
+        for (i, v) in self.tris.iter().enumerate() {
+            let face_n = face_normals[i / 3];
+
+            let mut normal = Vec3f::new(0.0, 0.0, 0.0);
+            for (f, angle) in position_faces[vert_positions[*v]].iter() {
+                if Vec3f::dot(&face_n, &face_normals[*f]) >= cos_crease {
+                    normal = normal + face_normals[*f] * *angle;
+                }
+            }
+            let normal = normal.normalize();
+
+            // Reuse a split of this vertex if one already has (about) this normal
+            let existing = splits[*v].iter().find(|n| {
+                Vec3f::dot(&normals[**n], &normal) > 1.0 - 1e-5
+            });
+
+            match existing {
+                Some(n) => tris.push(*n),
+                None => {
+                    splits[*v].push(sources.len());
+                    tris.push(sources.len());
+                    sources.push(*v);
+                    normals.push(normal);
+                }
+            }
+        }
+
+        self.select_vertices(&sources);
+        self.tris = tris;
+        self.normals = Some(normals);
+    }
+
+    /*
+        Generates tangent frames for normal mapping, following MikkTSpace's
+        approach, so that normal maps baked by other tools come out right.
+
+        The tangent points along increasing u, the bitangent along increasing
+        v. Both come out of each face's positions and uvs, get projected onto
+        the plane of the vertex normal, and are then averaged over the corners
+        that share a vertex, weighted by the angle at each corner. Faces whose
+        uvs are mirrored don't get averaged with those that aren't, so where
+        both meet at a vertex, it gets split in two.
+
+        Stored per vertex, as xyz the tangent and w the handedness, such that
+        bitangent = w * cross(normal, tangent). Only the tangent and its sign
+        get interpolated, and the bitangent is rebuilt in the fragment stage.
+
+        Tangents depend on the normals, so compute those first. A flat shaded
+        mesh gets hard-edged normals, which look the same.
+    */
+    pub fn compute_tangents(&mut self) {
+        if self.normals.is_none() {
+            // Within a degree counts as the same plane
+            self.compute_normals(1.0);
+        }
+
+        let num_tris = self.tris.len() / 3;
+
+        // Per corner: the projected, angle weighted tangent and bitangent of its face, and which way round its uvs go
+        let mut corners: Vec<(Vec3f, Vec3f, bool)> = Vec::with_capacity(self.tris.len());
+
+        {
+            let normals = self.normals.as_ref().unwrap();
+
+            for tri in 0..num_tris {
+                let p = |k: usize| Vec3f::from(&self.verts[self.tris[tri*3 + k]]);
+                let uv = |k: usize| self.uvs[self.tris[tri*3 + k]];
+
+                let e1 = p(1) - p(0);
+                let e2 = p(2) - p(0);
+                let duv1 = uv(1) - uv(0);
+                let duv2 = uv(2) - uv(0);
+
+                // Which way round the face's uvs go. Zero for degenerate uvs, which contribute nothing.
+                let r = duv1.x * duv2.y - duv2.x * duv1.y;
+                let (s_dir, t_dir) = if r != 0.0 {
+                    ((e1 * duv2.y - e2 * duv1.y) / r, (e2 * duv1.x - e1 * duv2.x) / r)
+                } else {
+                    (Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(0.0, 0.0, 0.0))
+                };
+
+                for k in 0..3 {
+                    let n = normals[self.tris[tri*3 + k]];
+                    let angle = self.corner_angle(tri, k);
+
+                    corners.push((
+                        project_unit(&s_dir, &n) * angle,
+                        project_unit(&t_dir, &n) * angle,
+                        r < 0.0));
+                }
+            }
+        }
+
+        // A vertex used with both handednesses gets a copy for the second one
+        let mut sources: Vec<usize> = (0..self.verts.len()).collect();
+        let mut claimed = vec![false; self.verts.len()];
+        let mut ids: HashMap<(usize, bool), usize> = HashMap::new();
+
+        let tris: Vec<usize> = self.tris.iter().enumerate().map(|(i, v)| {
+            *ids.entry((*v, corners[i].2)).or_insert_with(|| {
+                if !claimed[*v] {
+                    claimed[*v] = true;
+                    *v
+                } else {
+                    sources.push(*v);
+                    sources.len() - 1
+                }
+            })
+        }).collect();
+
+        // Sum up the corners of each vertex
+        let mut sums = vec![(Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(0.0, 0.0, 0.0)); sources.len()];
+        for (i, v) in tris.iter().enumerate() {
+            sums[*v].0 = sums[*v].0 + corners[i].0;
+            sums[*v].1 = sums[*v].1 + corners[i].1;
+        }
+
+        self.select_vertices(&sources);
+        self.tris = tris;
+
+        let tangents = self.normals.as_ref().unwrap().iter().zip(sums.iter()).map(|(n, (s_sum, t_sum))| {
+            // Without usable uvs, any tangent perpendicular to the normal will do
+            let mut tangent = project_unit(s_sum, n);
+            if tangent.length() == 0.0 {
+                tangent = any_perpendicular(n);
+            }
+
+            let w = if Vec3f::dot(&Vec3f::cross(n, &tangent), t_sum) < 0.0 { -1.0 } else { 1.0 };
+            Vec4f::new(tangent.x, tangent.y, tangent.z, w)
+        }).collect();
+
+        self.tangents = Some(tangents);
+    }
+}
+
+// v projected onto the plane perpendicular to unit vector n, and normalized. Zero if nothing's left.
+fn project_unit(v: &Vec3f, n: &Vec3f) -> Vec3f {
+    let p = *v - *n * Vec3f::dot(v, n);
+    let len = p.length();
+
+    if len > 1e-12 {
+        p / len
+    } else {
+        Vec3f::new(0.0, 0.0, 0.0)
     }
 }
 
-pub fn draw_mesh(mesh: &Mesh, tex: &Vec<Color>, transform: &Mat4x4f, cam_inv: &Mat4x4f, cam_proj: &Mat4x4f, screen: &mut Screen) {
-    let verts = &mesh.verts;
-    let tris = &mesh.tris;
-    let uvs = &mesh.uvs;
+fn any_perpendicular(n: &Vec3f) -> Vec3f {
+    let axis = if f32::abs(n.x) < 0.9 { Vec3f::new(1.0, 0.0, 0.0) } else { Vec3f::new(0.0, 1.0, 0.0) };
+    project_unit(&axis, n)
+}
+
+/*
+    Draws a mesh with the given vertex and fragment shaders. The vertex
+    shader runs once for each vertex, its output gets assembled into
+    triangles, clipped and set up for rasterization, and the fragment
+    shader then colors the covered pixels.
 
-    let num_tris = tris.len() / 3;
-        for i in 0..num_tris {
-            triangle(
-                &verts[tris[i*3 + 0]],
-                &verts[tris[i*3 + 1]],
-                &verts[tris[i*3 + 2]],
-                &uvs[i*3 + 0],
-                &uvs[i*3 + 1],
-                &uvs[i*3 + 2],
-                tex,
-                transform,
-                cam_inv,
-                cam_proj,
-                screen);
+    See tiles::draw_mesh_tiled for the multi-threaded version.
+*/
+pub fn draw_mesh<VS, FS>(
+    mesh: &Mesh,
+    transform: &Mat4x4f, camera: &Camera,
+    state: &RenderState,
+    vs: &VS, fs: &FS,
+    screen: &mut Screen)
+    where VS: VertexShader, FS: FragmentShader<VS::Output> {
+    let tris = process_geometry(mesh, transform, camera, vs, screen.width, screen.height);
+
+    let mut target = screen.target();
+
+    for tri in tris.iter() {
+        rasterize_triangle(&mut target, tri, state, fs);
+    }
+}
+
+/*
+    Runs the vertex stage for a mesh, and turns the results into screen-space
+    triangles, ready for rasterization.
+
+    This happens in two passes. The vertex pass runs the vertex shader once
+    for each vertex, into a buffer of clip-space vertices. Then primitive
+    assembly walks the index buffer, culls back faces, and picks each
+    remaining triangle's corners from that buffer, so a vertex shared by
+    several triangles only gets transformed once.
+
+    Culling goes by the winding of the clip-space corners. The determinant
+    of their x, y and w is, up to a positive factor, the triple product of
+    the corners as seen from the camera, which is negative for triangles
+    facing it. That holds for perspective and orthographic projections
+    alike, and doesn't need the divide by w, so it works before clipping.
+*/
+pub fn process_geometry<VS>(
+    mesh: &Mesh,
+    transform: &Mat4x4f, camera: &Camera,
+    vs: &VS,
+    width: usize, height: usize) -> Vec<ScreenTriangle<VS::Output>>
+    where VS: VertexShader {
+    let screen_dims = Vec2i::new(width as i32, height as i32);
+    let mut screen_tris = Vec::new();
+
+    // A singular model transform squashes the mesh flat, or to nothing at
+    // all. There's no sensible way to shade that, so we draw nothing rather
+    // than spray nan over the screen.
+    let transforms = match Transforms::new(transform, camera.view(), camera.proj()) {
+        Some(transforms) => transforms,
+        None => return screen_tris,
+    };
+
+    let (clip_verts, split_tris) = process_vertices(mesh, &transforms, vs);
+    let tris = split_tris.as_ref().unwrap_or(&mesh.tris);
+
+    for corners in tris.chunks_exact(3) {
+        let (a, b, c) = (&clip_verts[corners[0]], &clip_verts[corners[1]], &clip_verts[corners[2]]);
+
+        let xyw = |v: &ClipVertex<VS::Output>| Vec3f::new(v.pos.x, v.pos.y, v.pos.w);
+        if Vec3f::dot(&xyw(a), &Vec3f::cross(&xyw(b), &xyw(c))) >= 0.0 {
+            continue;
         }
+
+        setup_triangle(a, b, c, &screen_dims, &mut screen_tris);
+    }
+
+    screen_tris
+}
+
+/*
+    The vertex pass: runs the vertex shader once for each of the mesh's
+    vertices, in order.
+
+    Without vertex normals, each vertex gets the normal of its face instead,
+    which makes a vertex shared by faces that point different ways a
+    different vertex to each of them. Those get split up front, once for
+    each distinct face normal, which also gives back the index buffer to
+    assemble from. Triangles that lie in the same plane, like the two
+    halves of a quad, still share their vertices.
+*/
+fn process_vertices<VS>(mesh: &Mesh, transforms: &Transforms, vs: &VS) -> (Vec<ClipVertex<VS::Output>>, Option<Vec<usize>>)
+    where VS: VertexShader {
+    let vertex = |v: usize, normal: Vec3f| -> ClipVertex<VS::Output> {
+        let input = VertexInput {
+            position: mesh.verts[v],
+            uv: mesh.uvs[v],
+            normal: normal,
+            color: mesh.colors.as_ref().map_or(Color::white(), |c| c[v]),
+            tangent: mesh.tangents.as_ref().map_or(Vec4f::new(0.0, 0.0, 0.0, 0.0), |t| t[v]),
+        };
+
+        let (pos, varyings) = vs.vertex(&input, transforms);
+        ClipVertex::new(pos, varyings)
+    };
+
+    if let Some(normals) = mesh.normals.as_ref() {
+        let clip_verts = normals.iter().enumerate().map(|(v, n)| vertex(v, *n)).collect();
+        return (clip_verts, None);
+    }
+
+    let mut clip_verts = Vec::new();
+    let mut tris = Vec::with_capacity(mesh.tris.len());
+    let mut split: HashMap<(usize, [u32; 3]), usize> = HashMap::new();
+
+    for (t, corners) in mesh.tris.chunks_exact(3).enumerate() {
+        let normal = mesh.face_normal(t);
+        let bits = [normal.x.to_bits(), normal.y.to_bits(), normal.z.to_bits()];
+
+        for v in corners.iter() {
+            let index = *split.entry((*v, bits)).or_insert_with(|| {
+                clip_verts.push(vertex(*v, normal));
+                clip_verts.len() - 1
+            });
+            tris.push(index);
+        }
+    }
+
+    (clip_verts, Some(tris))
 }
 
 // Set an individual pixel's RGB color
@@ -206,79 +1195,183 @@ pub fn line(screen: &mut Screen, a: Vec2i, b: Vec2i, color: &Color) {
     let dy: i32 = -(y1-y0).abs();
     let sy: i32 = if y0<y1 { 1 } else { -1 };
 
-    let mut err = dx+dy;
-    let mut e2: i32;
+    let mut err = dx+dy;
+    let mut e2: i32;
+
+    loop {
+        set_color(screen, x0 as usize, y0 as usize, color);
+        e2 = 2 * err;
+        if e2 >= dy {
+            if x0 == x1 { break }
+            err += dy; x0 += sx;
+        }
+        if e2 <= dx {
+            if y0 == y1 { break }
+            err += dx; y0 += sy;
+        }
+    }
+}
+
+/*
+    A triangle that made it through clipping, in screen space, with 1/w
+    stored in each vertex's w. Carries its vertices snapped to the sub-pixel
+    grid, wound so that its area is positive, and its pixel bounding box
+    [min, max).
+*/
+pub struct ScreenTriangle<V: Varyings> {
+    pub verts: [ClipVertex<V>; 3],
+    pub points: [Vec2i; 3],
+    pub min: Vec2i,
+    pub max: Vec2i,
+}
+
+// Clips a triangle given in clip space, and sets up what's left of it for rasterization
+pub fn setup_triangle<V: Varyings>(
+    a: &ClipVertex<V>, b: &ClipVertex<V>, c: &ClipVertex<V>,
+    screen_dims: &Vec2i,
+    out: &mut Vec<ScreenTriangle<V>>) {
+    // Clip against the view frustum while we're still in homogeneous space,
+    // before dividing by w can blow up anything near or behind the camera
+    let poly = clip_triangle(&[*a, *b, *c]);
+
+    if poly.len() < 3 {
+        return;
+    }
+
+    let poly: Vec<ClipVertex<V>> = poly.iter().map(|v| {
+        // Normalize x,y,z by w to get valid point
+        let mut p = Vec4f::norm_by_w(&v.pos);
+
+        // Store the reciprocal of w, for perspective-correct interpolation in the fragment stage
+        p.w = 1.0 / p.w;
+
+        ClipVertex::new(p, v.varyings)
+    }).collect();
+
+    // Clipped polygon is convex, so we can fan it back out into triangles
+    for i in 1..poly.len()-1 {
+        let mut verts = [poly[0], poly[i], poly[i+1]];
+        let mut points = [
+            to_fixed(&verts[0].pos, screen_dims),
+            to_fixed(&verts[1].pos, screen_dims),
+            to_fixed(&verts[2].pos, screen_dims)];
+
+        // Triangles that snap to zero area cover no pixels. Those that
+        // come out wound the other way get flipped, so the rasterizer only
+        // ever sees one winding. Culling has already happened by now.
+        let area = EdgeFunction::new(&points[0], &points[1]).eval(&points[2]);
+        if area == 0 {
+            continue;
+        }
+        if area < 0 {
+            verts.swap(1, 2);
+            points.swap(1, 2);
+        }
+
+        let (min, max) = get_aabb(&points, screen_dims);
+        if min.x >= max.x || min.y >= max.y {
+            continue;
+        }
+
+        out.push(ScreenTriangle {
+            verts: verts,
+            points: points,
+            min: min,
+            max: max,
+        });
+    }
+
+    // Wireframe
+    // let wire_color = draw::Color::new(255, 255, 255);
+    // draw::triangle_wired(screen, &p1, &p2, &p3, &wire_color);
+}
+
+/*
+    Frustum clipping
+
+    Sutherland-Hodgman, run against each plane of the view volume in turn.
+    Planes are given as homogeneous vectors, such that a clip-space point p
+    lies inside when dot(plane, p) >= 0.
+
+    Note that to_pixelspace maps x,y in [-0.5, 0.5] onto the screen, so
+    that is the visible extent we clip x and y against. Depth runs from
+    0 at the near plane to 1 at the far plane, or the other way around with
+    reversed depth. The same two planes bound both. With an infinite far
+    plane, z never goes below 0 in front of the camera, so nothing there
+    gets clipped by it.
+*/
+
+const CLIP_EXTENT: f32 = 0.5;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ClipVertex<V: Varyings> {
+    pub pos: Vec4f,
+    pub varyings: V,
+}
 
-    loop {
-        set_color(screen, x0 as usize, y0 as usize, color);
-        e2 = 2 * err;
-        if e2 >= dy {
-            if x0 == x1 { break }
-            err += dy; x0 += sx;
-        }
-        if e2 <= dx {
-            if y0 == y1 { break }
-            err += dx; y0 += sy;
+impl<V: Varyings> ClipVertex<V> {
+    pub fn new(pos: Vec4f, varyings: V) -> ClipVertex<V> {
+        ClipVertex {
+            pos: pos,
+            varyings: varyings,
         }
     }
+
+    pub fn lerp(a: &ClipVertex<V>, b: &ClipVertex<V>, t: f32) -> ClipVertex<V> {
+        ClipVertex::new(
+            Vec4f::lerp(&a.pos, &b.pos, t),
+            V::lerp(&a.varyings, &b.varyings, t))
+    }
 }
 
-pub fn triangle(
-    p1: &Vec4f, p2: &Vec4f, p3: &Vec4f,
-    uv1: &Vec2f, uv2: &Vec2f, uv3: &Vec2f,
-    tex: &Vec<Color>,
-    obj_mat: &Mat4x4f, cam_inv: &Mat4x4f, cam_proj: &Mat4x4f,
-    screen: &mut Screen) {
-    // Todo: 
-    // - split this into multiple stages, of course, and
-    // - loop over a list of points instead
+fn clip_planes() -> [Vec4f; 6] {
+    [
+        Vec4f::new( 0.0,  0.0,  1.0, 0.0),         // near:   z >= 0
+        Vec4f::new( 0.0,  0.0, -1.0, 1.0),         // far:    z <= w
+        Vec4f::new( 1.0,  0.0,  0.0, CLIP_EXTENT), // left:   x >= -w/2
+        Vec4f::new(-1.0,  0.0,  0.0, CLIP_EXTENT), // right:  x <=  w/2
+        Vec4f::new( 0.0,  1.0,  0.0, CLIP_EXTENT), // bottom: y >= -w/2
+        Vec4f::new( 0.0, -1.0,  0.0, CLIP_EXTENT), // top:    y <=  w/2
+    ]
+}
 
-    // Obj to world
-    let p1 = *obj_mat * *p1;
-    let p2 = *obj_mat * *p2;
-    let p3 = *obj_mat * *p3;
+// Clips a clip-space triangle to the view volume, returning a convex polygon
+// with interpolated attributes. Empty if the triangle lies fully outside.
+pub fn clip_triangle<V: Varyings>(tri: &[ClipVertex<V>; 3]) -> Vec<ClipVertex<V>> {
+    let mut poly: Vec<ClipVertex<V>> = tri.to_vec();
 
-    let normal = Vec3f::cross(&(&(p2 - p1)).into(), &(&(p3 - p1)).into()); // todo: lol, fix dis ref/deref mess
-    let normal = normal.normalize();
+    for plane in clip_planes().iter() {
+        poly = clip_polygon(&poly, plane);
+        if poly.is_empty() {
+            break;
+        }
+    }
 
-    // backface culling
-    let cam_to_tri: Vec3f = Vec3f::from(&p1) - Vec3f::new(0.0, 0.0, -8.0);
-    if Vec3f::dot(&cam_to_tri, &normal) < 0.0 {
-        // Lighting
-        let light_dir = Vec3f::new(0.0, -0.5, 1.0).normalize();
-        let l_dot_n = f32::max(0.0, -Vec3f::dot(&normal, &light_dir));
+    poly
+}
 
-        // World to camera space
-        let p1 = *cam_inv * p1;
-        let p2 = *cam_inv * p2;
-        let p3 = *cam_inv * p3;
+fn clip_polygon<V: Varyings>(poly: &Vec<ClipVertex<V>>, plane: &Vec4f) -> Vec<ClipVertex<V>> {
+    let mut result: Vec<ClipVertex<V>> = Vec::with_capacity(poly.len() + 1);
 
-        // Projection
-        let p1 = *cam_proj * p1;
-        let p2 = *cam_proj * p2;
-        let p3 = *cam_proj * p3;
-        
-        // Normalize x,y,z by w to get valid point
-        let mut p1 = Vec4f::norm_by_w(&p1);
-        let mut p2 = Vec4f::norm_by_w(&p2);
-        let mut p3 = Vec4f::norm_by_w(&p3);
+    for i in 0..poly.len() {
+        let a = &poly[i];
+        let b = &poly[(i + 1) % poly.len()];
 
-        // Store depth reciprocal in w for use in fragment stage
-        p1.w = 1.0 / p1.w;
-        p2.w = 1.0 / p2.w;
-        p3.w = 1.0 / p3.w;
+        let dist_a = Vec4f::dot(*plane, a.pos);
+        let dist_b = Vec4f::dot(*plane, b.pos);
 
-        triangle_textured(
-            screen,
-            &p1, &p2, &p3,
-            uv1, uv2, uv3,
-            tex,
-            l_dot_n);
+        if dist_a >= 0.0 {
+            result.push(*a);
+        }
 
-        // Wireframe
-        // let wire_color = draw::Color::new(255, 255, 255);
-        // draw::triangle_wired(screen, &p1, &p2, &p3, &wire_color);
+        // Edge crosses the plane, so emit the intersection point
+        if (dist_a >= 0.0) != (dist_b >= 0.0) {
+            let t = dist_a / (dist_a - dist_b);
+            result.push(ClipVertex::lerp(a, b, t));
+        }
     }
+
+    result
 }
 
 pub fn triangle_wired(screen: &mut Screen, a: &Vec4f, b: &Vec4f, c: &Vec4f, color: &Color) {
@@ -296,154 +1389,239 @@ pub fn triangle_wired(screen: &mut Screen, a: &Vec4f, b: &Vec4f, c: &Vec4f, colo
     line(screen, c, a, color);
 }
 
-pub fn triangle_textured(
-    screen: &mut Screen,
-    a: &Vec4f, b: &Vec4f, c: &Vec4f,
-    a_uv: &Vec2f, b_uv: &Vec2f, c_uv: &Vec2f,
-    tex: &Vec<Color>,
-    l_dot_n: f32) {
-    let screen_dims = Vec2i::new(screen.width as i32, screen.height as i32);
-
-    // println!("{:?}", to_camspace(&Vec2i::new(screen_dims.x,screen_dims.y), &screen_dims));
-
-    // We generate a screen-pixel-space bounding box around the triangle
-    // to limit the region of pixels tested against the triangle
-    let a_s = to_pixelspace(&a, &screen_dims);
-    let b_s = to_pixelspace(&b, &screen_dims);
-    let c_s = to_pixelspace(&c, &screen_dims);
-    let a_s = clip_point(&a_s, &screen_dims);
-    let b_s = clip_point(&b_s, &screen_dims);
-    let c_s = clip_point(&c_s, &screen_dims);
-    let aabb = get_aabb(vec!(a_s,b_s,c_s), &screen_dims);
-
-    let edge_0 = *c - *a;
-    let edge_1 = *a - *c;
-    let edge_2 = *b - *a;
-
-    let tri_area_inv = 1.0 / signed_area(&a, &b, &c);
-
-    let step_x = (1.0 / screen_dims.x as f32) * tri_area_inv;
-    let step_y = (1.0 / screen_dims.y as f32) * tri_area_inv;
-
-    // Todo: something's wrong with values going negative instead of positive here
-    let bary_a_step_x = signed_area_step_x(step_x, b, c);
-    let bary_a_step_y = signed_area_step_y(step_y, b, c);
-    let bary_b_step_x = signed_area_step_x(step_x, c, a);
-    let bary_b_step_y = signed_area_step_y(step_y, c, a);
-    let bary_c_step_x = signed_area_step_x(step_x, a, b);
-    let bary_c_step_y = signed_area_step_y(step_y, a, b);
-
-    // println!("{}, {}, {}", bary_a_step_y, bary_b_step_y, bary_c_step_y);
-
-    let pix_camspace_bl = to_camspace(&Vec2i::new(aabb.0.x,aabb.0.y), &screen_dims);
-    let mut bary_a_row = signed_area(&b, &c, &pix_camspace_bl) * tri_area_inv;
-    let mut bary_b_row = signed_area(&c, &a, &pix_camspace_bl) * tri_area_inv;
-    let mut bary_c_row = signed_area(&a, &b, &pix_camspace_bl) * tri_area_inv;
+/*
+    Rasterizes a screen-space triangle into the target, limited to the pixels
+    it covers.
+
+    Coverage is decided with integer edge functions on the sub-pixel grid,
+    sampled at pixel centers. That makes it exact: a pixel center lying on
+    an edge shared by two triangles goes to exactly one of them, per the
+    top-left rule. It also means stepping from pixel to pixel is exact, so
+    the result for a pixel doesn't depend on where the target happens to
+    start. That's what lets the tiled rasterizer match this one exactly.
+
+    With multisampling, the same goes for each of the pixel's sample points
+    instead. The fragment shader runs at the pixel center, or if that's
+    outside the triangle, at the first sample that's in, so that varyings
+    don't get extrapolated past the triangle's edges.
+*/
+pub fn rasterize_triangle<V, FS>(
+    target: &mut RenderTarget,
+    tri: &ScreenTriangle<V>,
+    state: &RenderState,
+    fs: &FS)
+    where V: Varyings, FS: FragmentShader<V> {
+    let (a_v, b_v, c_v) = (&tri.verts[0].varyings, &tri.verts[1].varyings, &tri.verts[2].varyings);
+    let (a, b, c) = (&tri.verts[0].pos, &tri.verts[1].pos, &tri.verts[2].pos);
+    let (a_fx, b_fx, c_fx) = (&tri.points[0], &tri.points[1], &tri.points[2]);
+
+    // Limit our bounding box to the pixels the target covers
+    let x_min = i32::max(tri.min.x, target.x_min as i32);
+    let x_max = i32::min(tri.max.x, target.x_max as i32);
+    let y_min = i32::max(tri.min.y, target.y_min as i32);
+    let y_max = i32::min(tri.max.y, target.y_max as i32);
+
+    if x_min >= x_max || y_min >= y_max {
+        return;
+    }
 
-    // println!("[0,0]: {}, {}, {}", bary_a_row, bary_b_row, bary_c_row);
+    // Each edge function is zero on its edge and positive towards the
+    // opposite vertex, so it doubles as that vertex's barycentric weight
+    let edge_a = EdgeFunction::new(b_fx, c_fx);
+    let edge_b = EdgeFunction::new(c_fx, a_fx);
+    let edge_c = EdgeFunction::new(a_fx, b_fx);
+
+    let tri_area_inv = 1.0 / edge_c.eval(c_fx) as f32;
+
+    let bary_a_step_x = (edge_a.step_x * SUBPIXEL_STEPS) as f32 * tri_area_inv;
+    let bary_a_step_y = (edge_a.step_y * SUBPIXEL_STEPS) as f32 * tri_area_inv;
+    let bary_b_step_x = (edge_b.step_x * SUBPIXEL_STEPS) as f32 * tri_area_inv;
+    let bary_b_step_y = (edge_b.step_y * SUBPIXEL_STEPS) as f32 * tri_area_inv;
+    let bary_c_step_x = (edge_c.step_x * SUBPIXEL_STEPS) as f32 * tri_area_inv;
+    let bary_c_step_y = (edge_c.step_y * SUBPIXEL_STEPS) as f32 * tri_area_inv;
+
+    // Barycentric coordinates corrected for perspective, for interpolating vertex attributes
+    let perspective_weights = |bary_a: f32, bary_b: f32, bary_c: f32| -> (f32, f32, f32) {
+        let z = 1.0 / (a.w * bary_a + b.w * bary_b + c.w * bary_c);
+        (a.w * bary_a * z, b.w * bary_b * z, c.w * bary_c * z)
+    };
+
+    let interpolate = |bary_a: f32, bary_b: f32, bary_c: f32| -> V {
+        let (w_a, w_b, w_c) = perspective_weights(bary_a, bary_b, bary_c);
+        V::blend(a_v, b_v, c_v, w_a, w_b, w_c)
+    };
+
+    // Sample offsets, on the sub-pixel grid
+    let pattern = target.multisample.pattern();
+    let offsets: Vec<(i64, i64)> = pattern.iter().map(|o| {
+        (o.0 as i64 * SUBPIXEL_STEPS / 16, o.1 as i64 * SUBPIXEL_STEPS / 16)
+    }).collect();
+
+    // Fragments put aside in the A-buffer get resolved at their shading
+    // depth, so they're tested at it here too, or which samples they end
+    // up in would depend on the draw order
+    let deferred = target.defers(state);
+
+    // Edge values at the center of the first pixel, stepped exactly from there
+    let row_start = pixel_center(x_min, y_min);
+    let mut w_a_row = edge_a.eval(&row_start);
+    let mut w_b_row = edge_b.eval(&row_start);
+    let mut w_c_row = edge_c.eval(&row_start);
 
     // Loop over bounded pixels
-    for y in (aabb.0).y..(aabb.1).y {
-        let mut bary_a = bary_a_row;
-        let mut bary_b = bary_b_row;
-        let mut bary_c = bary_c_row;
-
-        for x in (aabb.0).x..(aabb.1).x {
-            let mut inside: bool = true;
+    for y in y_min..y_max {
+        let mut w_a = w_a_row;
+        let mut w_b = w_b_row;
+        let mut w_c = w_c_row;
 
+        for x in x_min..x_max {
             /*
-            If all three edge tests are positive, or we're a pixel right
+            If all three edge functions are positive, or we're a point right
             on the edge of a top-left triangle, then we rasterize
             */
+            let inside = |w_a: i64, w_b: i64, w_c: i64|
+                (w_a + edge_a.bias) >= 0 &&
+                (w_b + edge_b.bias) >= 0 &&
+                (w_c + edge_c.bias) >= 0;
+
+            // Depth after the divide by w is linear in screen space, for
+            // perspective and orthographic projections alike
+            let depth_at = |w_a: i64, w_b: i64, w_c: i64|
+                a.z * (w_a as f32 * tri_area_inv) +
+                b.z * (w_b as f32 * tri_area_inv) +
+                c.z * (w_c as f32 * tri_area_inv);
+
+            let mut covered = Coverage { mask: 0, depths: [0.0; MAX_SAMPLES] };
+            let mut shading_point = if inside(w_a, w_b, w_c) { Some((w_a, w_b, w_c)) } else { None };
+
+            for (sample, offset) in offsets.iter().enumerate() {
+                let w_a = w_a + edge_a.step_x * offset.0 + edge_a.step_y * offset.1;
+                let w_b = w_b + edge_b.step_x * offset.0 + edge_b.step_y * offset.1;
+                let w_c = w_c + edge_c.step_x * offset.0 + edge_c.step_y * offset.1;
+
+                if inside(w_a, w_b, w_c) {
+                    covered.mask |= 1 << sample;
+                    covered.depths[sample] = depth_at(w_a, w_b, w_c);
+
+                    // Any sample in sets the shading point, if the center hasn't already
+                    if shading_point.is_none() {
+                        shading_point = Some((w_a, w_b, w_c));
+                    }
+                }
+            }
 
-            test_topleft(&edge_0, bary_a, &mut inside);
-            test_topleft(&edge_1, bary_b, &mut inside);
-            test_topleft(&edge_2, bary_c, &mut inside);
-
-            if inside {
-                // interpolate UV values with barycentric coordinates
-
-                let z = 1.0 / (
-                    a.w * bary_a +
-                    b.w * bary_b +
-                    c.w * bary_c);
-
-                let curr_depth = get_depth(screen, x as usize, y as usize);
-
-                if z < curr_depth {
-                    let uv = 
-                    *a_uv * a.w * bary_a +
-                    *b_uv * b.w * bary_b +
-                    *c_uv * c.w * bary_c;
-
-                    let uv = uv * z;
-
-                    // transform UV values to texture space (taking care not to read out of bounds)
-                    // todo: get texture dimensions from texture, instead of hardcoding
-                    let uv_scr = ((uv.x * 63.999) as usize, ((1.0 - uv.y) * 63.999) as usize);
+            if let Some((w_a_s, w_b_s, w_c_s)) = shading_point {
+                let z = depth_at(w_a_s, w_b_s, w_c_s);
 
-                    // read from texture, without filtering
-                    let albedo = tex[uv_scr.0 * 64 + uv_scr.1];
-                    // let albedo = Color::blue();
+                let mut coverage = Coverage { mask: 0, ..covered };
+                for sample in (0..offsets.len()).filter(|s| covered.covers(*s)) {
+                    let sample_z = if deferred { z } else { covered.depths[sample] };
+                    let depth_pass = !state.depth_test || {
+                        let curr_depth = target.get_depth(x as usize, y as usize, sample);
+                        state.depth_func.test(target.depth_convention, sample_z, curr_depth)
+                    };
 
-                    // shade pixel
-                    let brightness = 0.1 + 0.9 * l_dot_n;
-                    let shaded_color = Color::new(
-                        (albedo.r as f32 * brightness) as u8,
-                        (albedo.g as f32 * brightness) as u8,
-                        (albedo.b as f32 * brightness) as u8);
+                    if depth_pass {
+                        coverage.mask |= 1 << sample;
+                    }
+                }
 
-                    set_color(screen, x as usize, y as usize, &shaded_color);
-                    set_depth(screen, x as usize, y as usize, z);
+                if coverage.mask != 0 {
+                    let bary_a = w_a_s as f32 * tri_area_inv;
+                    let bary_b = w_b_s as f32 * tri_area_inv;
+                    let bary_c = w_c_s as f32 * tri_area_inv;
+
+                    let varyings = interpolate(bary_a, bary_b, bary_c);
+
+                    // Derivatives, from where our neighbouring pixels would land
+                    let ddx = interpolate(bary_a + bary_a_step_x, bary_b + bary_b_step_x, bary_c + bary_c_step_x).add(&varyings.scale(-1.0));
+                    let ddy = interpolate(bary_a + bary_a_step_y, bary_b + bary_b_step_y, bary_c + bary_c_step_y).add(&varyings.scale(-1.0));
+
+                    let frag = Fragment {
+                        x: x as usize,
+                        y: y as usize,
+                        depth: z,
+                        varyings: varyings,
+                        ddx: ddx,
+                        ddy: ddy,
+                    };
+
+                    if let Some(color) = fs.fragment(&frag) {
+                        target.write_fragment(x as usize, y as usize, z, &coverage, &color, state);
+                    }
                 }
             }
 
-            bary_a += bary_a_step_x;
-            bary_b += bary_b_step_x;
-            bary_c += bary_c_step_x;
+            w_a += edge_a.step_x * SUBPIXEL_STEPS;
+            w_b += edge_b.step_x * SUBPIXEL_STEPS;
+            w_c += edge_c.step_x * SUBPIXEL_STEPS;
         }
 
-        bary_a_row += bary_a_step_y;
-        bary_b_row += bary_b_step_y;
-        bary_c_row += bary_c_step_y;
+        w_a_row += edge_a.step_y * SUBPIXEL_STEPS;
+        w_b_row += edge_b.step_y * SUBPIXEL_STEPS;
+        w_c_row += edge_c.step_y * SUBPIXEL_STEPS;
     }
 }
 
 /*
-    Note:
-    reference implementation, performance is not great
-    working with float approx this way feels nasty (use fixed?)
-    not having ternary statements makes this code lengthy
+    Fixed point screen space
+
+    Vertices are snapped to a grid of 1/256th of a pixel, in 24.8 fixed
+    point, with y pointing down. Edge functions multiply two such numbers,
+    so we evaluate them in i64, where they can't overflow for any sensible
+    screen size.
 */
-fn test_topleft(edge: &Vec4f, w: f32, inside: &mut bool) {
-     if approx_eq(w, 0.0) {
-            // If lying on an edge, are we a top-left?
-            *inside &= (approx_eq(edge.y, 0.0) && edge.x > 0.0) || edge.y > 0.0;
-        } else {
-            // If we're on the positive side of the half-plane defined by the edge
-            *inside &= w > 0.0;
-        }
+
+const SUBPIXEL_BITS: i32 = 8;
+const SUBPIXEL_STEPS: i64 = 1 << SUBPIXEL_BITS;
+
+fn to_fixed(point: &Vec4f, screen_dims: &Vec2i) -> Vec2i {
+    let x = (point.x + 0.5) * screen_dims.x as f32;
+    let y = (0.5 - point.y) * screen_dims.y as f32; // Note, we're inverting y here
+    Vec2i::new(
+        (x * SUBPIXEL_STEPS as f32).round() as i32,
+        (y * SUBPIXEL_STEPS as f32).round() as i32)
 }
 
-fn approx_eq(a: f32, b: f32) -> bool {
-    /* 
-        Using library function that's fully compliant with floating point spec
-        and is correct for every possible combination of values.
+fn pixel_center(x: i32, y: i32) -> Vec2i {
+    Vec2i::new(
+        (x << SUBPIXEL_BITS) + (1 << (SUBPIXEL_BITS - 1)),
+        (y << SUBPIXEL_BITS) + (1 << (SUBPIXEL_BITS - 1)))
+}
 
-        This makes the function ***stupidly*** slow, adding whole miliseconds.
-    */
-    // a.approx_eq(&b, 2.0 * ::std::f32::EPSILON, 2)
+/*
+    The edge function for a -> b, in the form step_x * x + step_y * y + c.
+    It is positive to the right of the edge, as seen on screen.
 
-    /*
-        My much less correct implementation that still gets me results, at a
-        small fraction of the cost.
-    */
-    f32::abs(a - b) < std::f32::EPSILON
+    Bias implements the top-left rule. A top edge is exactly horizontal
+    with the triangle below it, a left edge goes up with the triangle to
+    its right. Pixels exactly on any other edge get pushed out by the bias.
+*/
+struct EdgeFunction {
+    step_x: i64,
+    step_y: i64,
+    c: i64,
+    bias: i64,
+}
 
-    /*
-        Todo: This approx_eq test, as used to determine whether a pixel lies on
-        a triangle edge, is a prime candidate for fixed point logic.
-    */
+impl EdgeFunction {
+    fn new(a: &Vec2i, b: &Vec2i) -> EdgeFunction {
+        let step_x = (a.y - b.y) as i64;
+        let step_y = (b.x - a.x) as i64;
+        let is_top = step_x == 0 && step_y > 0;
+        let is_left = step_x > 0;
+
+        EdgeFunction {
+            step_x: step_x,
+            step_y: step_y,
+            c: -(step_x * a.x as i64 + step_y * a.y as i64),
+            bias: if is_top || is_left { 0 } else { -1 },
+        }
+    }
+
+    fn eval(&self, p: &Vec2i) -> i64 {
+        self.step_x * p.x as i64 + self.step_y * p.y as i64 + self.c
+    }
 }
 
 fn to_pixelspace(point: &Vec4f, screen_dims: &Vec2i) -> Vec2i {
@@ -478,40 +1656,23 @@ fn clip_point(point: &Vec2i, screen_dims: &Vec2i) -> Vec2i {
         i32::min(i32::max(0, point.y), screen_dims.y-1))
 }
 
-// Calculate a screen-space bounding box for a given polygon/triangle
-fn get_aabb(points: Vec<Vec2i>, screen_dims: &Vec2i) -> (Vec2i, Vec2i){
-    let mut x_min: i32 = screen_dims.x;
-    let mut y_min: i32 = screen_dims.y;
-    let mut x_max: i32 = 0;
-    let mut y_max: i32 = 0;
-
-    for p in points.iter() {
-        if (p.x) < x_min {x_min = p.x;}
-        if (p.y) < y_min {y_min = p.y;}
-        if (p.x) > x_max {x_max = p.x;}
-        if (p.y) > y_max {y_max = p.y;}
-    }
+// Calculate the range of pixels [min, max) whose centers could lie in the triangle
+fn get_aabb(points: &[Vec2i; 3], screen_dims: &Vec2i) -> (Vec2i, Vec2i) {
+    let x_min = points.iter().map(|p| p.x).min().unwrap();
+    let y_min = points.iter().map(|p| p.y).min().unwrap();
+    let x_max = points.iter().map(|p| p.x).max().unwrap();
+    let y_max = points.iter().map(|p| p.y).max().unwrap();
 
-    // Todo: improve things so we don't need this padding
+    // Every pixel the box overlaps at all, as multisampling can find
+    // coverage anywhere inside a pixel, not just at its center
     (
-        Vec2i::new(x_min-1, y_min-1),
-        Vec2i::new(x_max+1, y_max+1)
-    ) 
-}
-
-// Todo: only using these as Vec2, so can we make a From<Vec3> that returns same mem reinterpreted as Vec2?
-fn signed_area(a: &Vec4f, b: &Vec4f, p: &Vec4f) -> f32 {
-    (p.x - a.x) * (b.y - a.y) - (p.y - a.y) * (b.x - a.x)
-}
-
-fn signed_area_step_x(step_x: f32, a: &Vec4f, b: &Vec4f) -> f32 {
-    // Uh, these are the reverse of what I derived them to be. But Giesen has them this way. Why?
-    // because of the y inversion I have elsewhere?
-    -step_x * (a.y - b.y) 
-}
-
-fn signed_area_step_y(step_y: f32, a: &Vec4f, b: &Vec4f) -> f32 {
-    step_y * (b.x - a.x)
+        Vec2i::new(
+            i32::max(0, x_min >> SUBPIXEL_BITS),
+            i32::max(0, y_min >> SUBPIXEL_BITS)),
+        Vec2i::new(
+            i32::min(screen_dims.x, ((x_max - 1) >> SUBPIXEL_BITS) + 1),
+            i32::min(screen_dims.y, ((y_max - 1) >> SUBPIXEL_BITS) + 1))
+    )
 }
 
 // Bresenham-style circle drawing algorithm, as per this wonderful paper:
@@ -546,15 +1707,111 @@ pub fn clear_color(screen: &mut Screen) {
             screen.color[offset +2] = 0;
         }
     }
+
+    for c in screen.sample_color.iter_mut() {
+        *c = 0;
+    }
 }
 
 pub fn clear_depth(screen: &mut Screen) {
+    let clear_value = screen.depth_convention.clear_value();
     let pitch = screen.width;
     for y in 0..screen.height {
         for x in 0..screen.width {
             let offset = y * pitch + x;
-            screen.depth[offset] = 1000.0;
+            screen.depth[offset] = clear_value;
+        }
+    }
+
+    for d in screen.sample_depth.iter_mut() {
+        *d = clear_value;
+    }
+}
+
+/*
+    Blends the A-buffer into the color buffer, back to front, and empties
+    it for the next frame. Call this once all of a frame's geometry has
+    been drawn. Fragments get depth tested again, so those that opaque
+    geometry drawn after them ended up covering get dropped. Does nothing
+    with InDrawOrder transparency, as there's nothing in the A-buffer then.
+
+    When multisampling, this blends into the samples, each of them getting
+    the fragments that cover it. Resolve those afterwards.
+*/
+pub fn resolve_transparency(screen: &mut Screen) {
+    let convention = screen.depth_convention;
+    let samples = screen.multisample.samples();
+    let (sample_color, sample_depth, fragments) = screen.sample_buffers();
+
+    for (i, frags) in fragments.iter_mut().enumerate() {
+        if frags.is_empty() {
+            continue;
+        }
+
+        // Farthest first. The sort is stable, so fragments at equal depth stay in draw order.
+        frags.sort_by(|a, b| {
+            if convention.closer(b.depth, a.depth) {
+                Ordering::Less
+            } else if convention.closer(a.depth, b.depth) {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        });
+
+        for sample in 0..samples {
+            let index = i * samples + sample;
+            let offset = index * 3;
+            let mut color = Color::new(sample_color[offset], sample_color[offset+1], sample_color[offset+2]);
+
+            for frag in frags.iter().filter(|f| f.coverage & (1 << sample) != 0) {
+                if frag.depth_func.test(convention, frag.depth, sample_depth[index]) {
+                    color = frag.blend.blend(&frag.color, &color);
+                }
+            }
+
+            sample_color[offset] = color.r;
+            sample_color[offset+1] = color.g;
+            sample_color[offset+2] = color.b;
+        }
+
+        frags.clear();
+    }
+}
+
+/*
+    Averages each pixel's samples into the color buffer, and puts the
+    nearest of their depths in the depth buffer. Does nothing without
+    multisampling, as everything went straight into those buffers then.
+*/
+pub fn resolve_samples(screen: &mut Screen) {
+    if screen.multisample == Multisample::Off {
+        return;
+    }
+
+    let samples = screen.multisample.samples();
+    let convention = screen.depth_convention;
+
+    let pixels = screen.color.chunks_exact_mut(3)
+        .zip(screen.depth.iter_mut())
+        .zip(screen.sample_color.chunks_exact(samples * 3))
+        .zip(screen.sample_depth.chunks_exact(samples));
+
+    for (((color, depth), sample_color), sample_depth) in pixels {
+        let mut sum = [0u32; 3];
+        for rgb in sample_color.chunks_exact(3) {
+            for (s, c) in sum.iter_mut().zip(rgb.iter()) {
+                *s += *c as u32;
+            }
         }
+
+        for (c, s) in color.iter_mut().zip(sum.iter()) {
+            *c = ((s + samples as u32 / 2) / samples as u32) as u8;
+        }
+
+        *depth = sample_depth.iter().fold(sample_depth[0], |nearest, d| {
+            if convention.closer(*d, nearest) { *d } else { nearest }
+        });
     }
 }
 
@@ -613,6 +1870,7 @@ pub fn clear_depth(screen: &mut Screen) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::texture::*;
 
     #[test]
     fn test_cam_pixel_space_conversion() {
@@ -624,13 +1882,759 @@ mod tests {
         assert_eq!(cam_space, cam_space_b);
     }
 
+    fn clip_vertex(x: f32, y: f32, z: f32, w: f32) -> ClipVertex<Vec2f> {
+        ClipVertex::new(Vec4f::new(x, y, z, w), Vec2f::new(x, y))
+    }
+
+    #[test]
+    fn test_clip_triangle_inside() {
+        let tri = [
+            clip_vertex(-0.1, -0.1, 0.5, 1.0),
+            clip_vertex( 0.0,  0.1, 0.5, 1.0),
+            clip_vertex( 0.1, -0.1, 0.5, 1.0)];
+
+        let poly = clip_triangle(&tri);
+
+        assert_eq!(poly, tri.to_vec());
+    }
+
+    #[test]
+    fn test_clip_triangle_outside() {
+        // Fully behind the camera
+        let tri = [
+            clip_vertex(-0.1, -0.1, -2.0, -1.0),
+            clip_vertex( 0.0,  0.1, -2.0, -1.0),
+            clip_vertex( 0.1, -0.1, -2.0, -1.0)];
+
+        assert!(clip_triangle(&tri).is_empty());
+    }
+
+    #[test]
+    fn test_clip_triangle_near_plane() {
+        // One vertex behind the near plane, should split into a quad
+        let tri = [
+            clip_vertex(-0.1, 0.0,  0.5, 1.0),
+            clip_vertex( 0.0, 0.0, -0.5, 0.5),
+            clip_vertex( 0.1, 0.0,  0.5, 1.0)];
+
+        let poly = clip_triangle(&tri);
+
+        assert_eq!(poly.len(), 4);
+        for v in poly.iter() {
+            assert!(v.pos.z >= 0.0);
+            assert!(v.pos.w > 0.0);
+        }
+
+        // Attributes are interpolated along with position
+        assert_eq!(poly[1].pos.z, 0.0);
+        assert!(f32::abs(poly[1].varyings.x - poly[1].pos.x) < std::f32::EPSILON);
+    }
+
+    #[test]
+    fn test_mip_selection_from_uv_gradient() {
+        // Color each mip level of a 256x256 texture differently, so we can tell which one got sampled
+        let mut tex = Texture::new(256, 256, TextureLayout::RowMajor, vec![Color::black(); 256 * 256]);
+        tex.generate_mips();
+        for (i, mip) in tex.mips.iter_mut().enumerate() {
+            for p in mip.pixels.iter_mut() {
+                *p = Color::new((i as u8 + 1) * 10, 0, 0);
+            }
+        }
+
+        // A quad covering the whole 64x64 screen, so we step 4 texels per pixel
+        let mut screen = Screen::new(64, 64);
+        let shader = UnlitShader { tex: &tex };
+        let vertex = |x: f32, y: f32| ClipVertex::new(
+            Vec4f::new(x, y, 0.5, 1.0),
+            StandardVaryings {
+                uv: Vec2f::new(x + 0.5, y + 0.5),
+                normal: Vec3f::new(0.0, 0.0, -1.0),
+                color: Vec4f::new(1.0, 1.0, 1.0, 1.0),
+                world_pos: Vec3f::new(x, y, 0.5),
+                tangent: Vec4f::new(0.0, 0.0, 0.0, 0.0),
+            });
+        let bl = vertex(-0.5, -0.5);
+        let tl = vertex(-0.5, 0.5);
+        let tr = vertex(0.5, 0.5);
+        let br = vertex(0.5, -0.5);
+
+        let screen_dims = Vec2i::new(64, 64);
+        let mut tris = Vec::new();
+        setup_triangle(&bl, &tl, &tr, &screen_dims, &mut tris);
+        setup_triangle(&bl, &tr, &br, &screen_dims, &mut tris);
+
+        let mut target = screen.target();
+        for tri in tris.iter() {
+            rasterize_triangle(&mut target, tri, &RenderState::new(), &shader);
+        }
+
+        // log2(4) = 2, which lives in mips[1]
+        let offset = (32 * 64 + 32) * 3;
+        assert_eq!(screen.color[offset], 20);
+    }
+
+    // Passes the interpolated varying straight through as a color
+    struct PassThroughShader;
+
+    impl VertexShader for PassThroughShader {
+        type Output = Vec4f;
+
+        fn vertex(&self, input: &VertexInput, transforms: &Transforms) -> (Vec4f, Vec4f) {
+            (transforms.model_view_projection * input.position, input.color.to_vec4())
+        }
+    }
+
+    impl FragmentShader<Vec4f> for PassThroughShader {
+        fn fragment(&self, frag: &Fragment<Vec4f>) -> Option<Color> {
+            Some(Color::from_vec4(&frag.varyings))
+        }
+    }
+
+    #[test]
+    fn test_custom_shader_varyings() {
+        use crate::resources::create_test_triangle;
+
+        // A single red triangle, drawn straight into clip space
+        let mut mesh = create_test_triangle();
+        mesh.colors = Some(vec![Color::red(); 3]);
+        for v in mesh.verts.iter_mut() {
+            *v = Vec4f::new(v.x * 0.25, v.y * 0.25, 0.5, 1.0);
+        }
+
+        // A unit orthographic camera at the origin leaves clip space as is
+        let mut screen = Screen::new(32, 32);
+        let camera = Camera::orthographic(1.0, 0.0, 1.0, 1.0);
+        let shader = PassThroughShader;
+        draw_mesh(&mesh, &Mat4x4f::identity(), &camera, &RenderState::new(), &shader, &shader, &mut screen);
+
+        // Triangle covers the top-left of the screen center
+        let offset = (12 * 32 + 12) * 3;
+        assert_eq!(&screen.color[offset..offset+3], &[255, 0, 0]);
+        assert_eq!(get_depth(&mut screen, 12, 12), 0.5);
+
+        // Bottom-right is untouched
+        let offset = (20 * 32 + 20) * 3;
+        assert_eq!(&screen.color[offset..offset+3], &[0, 0, 0]);
+    }
+
+    // A screen-filling quad at the given clip space depth, for drawing with a unit orthographic camera
+    fn clip_space_quad(z: f32, color: Color) -> Mesh {
+        clip_space_rect(0.5, z, color)
+    }
+
+    // Likewise, but only reaching right up to x
+    fn clip_space_rect(x: f32, z: f32, color: Color) -> Mesh {
+        let verts = vec![
+            Vec4f::new(-0.5, -0.5, z, 1.0),
+            Vec4f::new(-0.5,  0.5, z, 1.0),
+            Vec4f::new(   x,  0.5, z, 1.0),
+            Vec4f::new(   x, -0.5, z, 1.0),
+        ];
+        let mut mesh = Mesh::new(verts, vec![0, 1, 2, 0, 2, 3], vec![Vec2f::new(0.0, 0.0); 4]).unwrap();
+        mesh.colors = Some(vec![color; 4]);
+        mesh
+    }
+
+    #[test]
+    fn test_depth_funcs() {
+        let camera = Camera::orthographic(1.0, 0.0, 1.0, 1.0);
+        let shader = PassThroughShader;
+        let identity = Mat4x4f::identity();
+
+        // Whether a green quad in front of, level with or behind a red one shows up
+        let cases = [
+            (DepthFunc::Never,     [false, false, false]),
+            (DepthFunc::Less,      [true,  false, false]),
+            (DepthFunc::LessEqual, [true,  true,  false]),
+            (DepthFunc::Equal,     [false, true,  false]),
+            (DepthFunc::Greater,   [false, false, true ]),
+            (DepthFunc::Always,    [true,  true,  true ]),
+        ];
+
+        for convention in [DepthConvention::Standard, DepthConvention::Reversed].iter() {
+            // Whichever way depth runs, the first of these is in front
+            let depths = match convention {
+                DepthConvention::Standard => [0.25, 0.5, 0.75],
+                DepthConvention::Reversed => [0.75, 0.5, 0.25],
+            };
+
+            for (func, expected) in cases.iter() {
+                for (depth, visible) in depths.iter().zip(expected.iter()) {
+                    let mut screen = Screen::with_depth_convention(8, 8, *convention);
+                    draw_mesh(&clip_space_quad(0.5, Color::red()), &identity, &camera, &RenderState::new(), &shader, &shader, &mut screen);
+
+                    let mut state = RenderState::new();
+                    state.depth_func = *func;
+                    draw_mesh(&clip_space_quad(*depth, Color::green()), &identity, &camera, &state, &shader, &shader, &mut screen);
+
+                    let expected_color = if *visible { [0, 255, 0] } else { [255, 0, 0] };
+                    assert_eq!(&screen.color[0..3], &expected_color, "{:?} {:?} at depth {}", convention, func, depth);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_depth_test_and_write() {
+        let camera = Camera::orthographic(1.0, 0.0, 1.0, 1.0);
+        let shader = PassThroughShader;
+        let identity = Mat4x4f::identity();
+        let near = clip_space_quad(0.25, Color::red());
+        let far = clip_space_quad(0.75, Color::green());
+
+        // Without depth writes, the near quad leaves the depth buffer alone, so the far one still gets drawn
+        let mut screen = Screen::new(8, 8);
+        let mut state = RenderState::new();
+        state.depth_write = false;
+        draw_mesh(&near, &identity, &camera, &state, &shader, &shader, &mut screen);
+        assert_eq!(&screen.color[0..3], &[255, 0, 0]);
+        assert_eq!(screen.depth[0], 1.0);
+        draw_mesh(&far, &identity, &camera, &RenderState::new(), &shader, &shader, &mut screen);
+        assert_eq!(&screen.color[0..3], &[0, 255, 0]);
+        assert_eq!(screen.depth[0], 0.75);
+
+        // Without the depth test, the far quad draws over the near one, and still writes its depth
+        let mut screen = Screen::new(8, 8);
+        draw_mesh(&near, &identity, &camera, &RenderState::new(), &shader, &shader, &mut screen);
+        let mut state = RenderState::new();
+        state.depth_test = false;
+        draw_mesh(&far, &identity, &camera, &state, &shader, &shader, &mut screen);
+        assert_eq!(&screen.color[0..3], &[0, 255, 0]);
+        assert_eq!(screen.depth[0], 0.75);
+
+        // Depth only, the color stays as it was
+        draw_mesh(&near, &identity, &camera, &RenderState::depth_only(), &shader, &shader, &mut screen);
+        assert_eq!(&screen.color[0..3], &[0, 255, 0]);
+        assert_eq!(screen.depth[0], 0.25);
+    }
+
+    #[test]
+    fn test_blend_modes() {
+        let dst = Color::new(200, 100, 0);
+        let src = Color::rgba(0, 100, 255, 64);
+
+        assert_eq!(BlendMode::Opaque.blend(&src, &dst), Color::new(0, 100, 255));
+        assert_eq!(BlendMode::Alpha.blend(&src, &dst), Color::new(150, 100, 64));
+        assert_eq!(BlendMode::Additive.blend(&src, &dst), Color::new(200, 125, 64));
+        assert_eq!(BlendMode::Multiplicative.blend(&src, &dst), Color::new(150, 85, 0));
+        assert_eq!(BlendMode::Premultiplied.blend(&src.with_alpha(128), &dst), Color::new(100, 150, 255));
+
+        // Fully opaque alpha blending is a plain overwrite, fully transparent leaves things be
+        for mode in [BlendMode::Alpha, BlendMode::Premultiplied].iter() {
+            assert_eq!(mode.blend(&src.with_alpha(255), &dst), Color::new(0, 100, 255));
+        }
+        for mode in [BlendMode::Alpha, BlendMode::Additive, BlendMode::Multiplicative].iter() {
+            assert_eq!(mode.blend(&src.with_alpha(0), &dst), dst);
+        }
+
+        // Light adds up, but stays in range
+        assert_eq!(BlendMode::Additive.blend(&Color::new(100, 100, 100), &dst), Color::new(255, 200, 100));
+    }
+
+    #[test]
+    fn test_alpha_blending_and_test() {
+        let camera = Camera::orthographic(1.0, 0.0, 1.0, 1.0);
+        let shader = PassThroughShader;
+        let identity = Mat4x4f::identity();
+        let red = clip_space_quad(0.5, Color::red());
+        let green = clip_space_quad(0.25, Color::rgba(0, 255, 0, 64));
+
+        // Green at quarter opacity, over red
+        let mut screen = Screen::new(8, 8);
+        draw_mesh(&red, &identity, &camera, &RenderState::new(), &shader, &shader, &mut screen);
+        draw_mesh(&green, &identity, &camera, &RenderState::transparent(), &shader, &shader, &mut screen);
+        assert_eq!(&screen.color[0..3], &[191, 64, 0]);
+        assert_eq!(screen.depth[0], 0.5);
+
+        // Cut out below the threshold, so no color and no depth
+        let mut state = RenderState::new();
+        state.alpha_test = Some(0.5);
+        let mut screen = Screen::new(8, 8);
+        draw_mesh(&red, &identity, &camera, &RenderState::new(), &shader, &shader, &mut screen);
+        draw_mesh(&green, &identity, &camera, &state, &shader, &shader, &mut screen);
+        assert_eq!(&screen.color[0..3], &[255, 0, 0]);
+        assert_eq!(screen.depth[0], 0.5);
+
+        // Above it, opaque
+        state.alpha_test = Some(0.2);
+        draw_mesh(&green, &identity, &camera, &state, &shader, &shader, &mut screen);
+        assert_eq!(&screen.color[0..3], &[0, 255, 0]);
+        assert_eq!(screen.depth[0], 0.25);
+    }
+
+    #[test]
+    fn test_order_independent_transparency() {
+        let camera = Camera::orthographic(1.0, 0.0, 1.0, 1.0);
+        let shader = PassThroughShader;
+        let identity = Mat4x4f::identity();
+        let state = RenderState::transparent();
+        let red = clip_space_quad(0.5, Color::rgba(255, 0, 0, 128));
+        let green = clip_space_quad(0.25, Color::rgba(0, 255, 0, 128));
+        let blue = clip_space_quad(0.75, Color::blue());
+
+        let draw = |meshes: &[&Mesh], transparency: TransparencyMode| -> Screen {
+            let mut screen = Screen::new(8, 8);
+            screen.set_transparency(transparency);
+            for mesh in meshes.iter() {
+                let state = if mesh.colors.as_ref().unwrap()[0].a == 255 { RenderState::new() } else { state };
+                draw_mesh(mesh, &identity, &camera, &state, &shader, &shader, &mut screen);
+            }
+            resolve_transparency(&mut screen);
+            screen
+        };
+
+        // Back to front is what blending in draw order gets right
+        let expected = draw(&[&blue, &red, &green], TransparencyMode::InDrawOrder);
+        assert_eq!(&expected.color[0..3], &[64, 128, 63]);
+        assert!(expected.fragments.is_empty());
+        assert_ne!(draw(&[&blue, &green, &red], TransparencyMode::InDrawOrder).color, expected.color);
+
+        // And the A-buffer gets it right in any order
+        for order in [[&blue, &green, &red], [&green, &blue, &red], [&red, &green, &blue]].iter() {
+            let screen = draw(order, TransparencyMode::OrderIndependent);
+            assert_eq!(screen.color, expected.color);
+            assert_eq!(screen.depth[0], 0.75);
+            assert_eq!(screen.fragments.len(), 8 * 8);
+            assert!(screen.fragments.iter().all(|f| f.is_empty()));
+        }
+
+        // Opaque geometry drawn afterwards hides what's behind it
+        let front = clip_space_quad(0.4, Color::blue());
+        let screen = draw(&[&red, &green, &front], TransparencyMode::OrderIndependent);
+        assert_eq!(&screen.color[0..3], &[0, 128, 127]);
+    }
+
+    #[test]
+    fn test_multisample_patterns() {
+        let modes = [(Multisample::Off, 1), (Multisample::X2, 2), (Multisample::X4, 4), (Multisample::X8, 8)];
+
+        for &(multisample, samples) in modes.iter() {
+            let pattern = multisample.pattern();
+            assert_eq!(multisample.samples(), samples);
+            assert!(samples <= MAX_SAMPLES);
+
+            // Inside the pixel, and no two in the same spot
+            for (i, a) in pattern.iter().enumerate() {
+                assert!(a.0.abs() < 8 && a.1.abs() < 8);
+                assert!(pattern[i+1..].iter().all(|b| a != b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_multisample_coverage_and_depth() {
+        let camera = Camera::orthographic(1.0, 0.0, 1.0, 1.0);
+        let shader = PassThroughShader;
+        let identity = Mat4x4f::identity();
+
+        let draw = |meshes: &[&Mesh], multisample: Multisample| -> Screen {
+            let mut screen = Screen::new(4, 4);
+            screen.set_multisample(multisample);
+            for mesh in meshes.iter() {
+                draw_mesh(mesh, &identity, &camera, &RenderState::new(), &shader, &shader, &mut screen);
+            }
+            resolve_samples(&mut screen);
+            screen
+        };
+
+        // Reaching up to the center of the second pixel, which half of its 4 samples lie left of
+        let red = clip_space_rect(-0.125, 0.5, Color::red());
+
+        // Without multisampling, that center is on a right edge, so the pixel's not covered at all
+        let aliased = draw(&[&red], Multisample::Off);
+        assert_eq!(&aliased.color[0..9], &[255, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let smooth = draw(&[&red], Multisample::X4);
+        assert_eq!(&smooth.color[0..9], &[255, 0, 0, 128, 0, 0, 0, 0, 0]);
+        assert_eq!(&smooth.depth[0..3], &[0.5, 0.5, 1.0]);
+
+        // Depth is tested per sample, so further away, the samples the near quad covers stay its own
+        let near = clip_space_rect(-0.125, 0.25, Color::green());
+        let far = clip_space_quad(0.5, Color::red());
+        for order in [[&near, &far], [&far, &near]].iter() {
+            let screen = draw(order, Multisample::X4);
+            assert_eq!(&screen.color[0..9], &[0, 255, 0, 128, 128, 0, 255, 0, 0]);
+            assert_eq!(screen.depth[1], 0.25);
+        }
+    }
+
+    #[test]
+    fn test_multisample_shades_once_per_pixel() {
+        use crate::resources::create_test_triangle;
+
+        let mut mesh = create_test_triangle();
+        for v in mesh.verts.iter_mut() {
+            *v = Vec4f::new(v.x * 0.3, v.y * 0.3, 0.5, 1.0);
+        }
+        let camera = Camera::orthographic(1.0, 0.0, 1.0, 1.0);
+
+        let shaded = |multisample: Multisample| -> Vec<u32> {
+            let shader = CoverageShader {
+                width: 32,
+                counts: std::cell::RefCell::new(vec![0; 32 * 32]),
+            };
+
+            let mut screen = Screen::new(32, 32);
+            screen.set_multisample(multisample);
+            draw_mesh(&mesh, &Mat4x4f::identity(), &camera, &RenderState::new(), &DepthOnlyShader, &shader, &mut screen);
+            shader.counts.into_inner()
+        };
+
+        // However many samples it covers, a pixel runs the shader at most once.
+        // Along the edges, there are pixels with samples in but not their centers.
+        let aliased = shaded(Multisample::Off);
+        let smooth = shaded(Multisample::X8);
+        assert!(smooth.iter().all(|c| *c <= 1));
+        assert!(aliased.iter().zip(smooth.iter()).all(|(a, s)| a <= s));
+        assert!(smooth.iter().sum::<u32>() > aliased.iter().sum::<u32>());
+    }
+
+    #[test]
+    fn test_culling_uses_camera_position() {
+        use crate::resources::create_cube;
+
+        // Looking at a cube from behind, the +z face is the one facing us
+        let mesh = create_cube();
+        let mut camera = Camera::perspective(80.0, 0.1, 100.0, 1.0);
+        camera.set_position(&Vec3f::new(0.0, 0.0, 8.0));
+        camera.look_at(&Vec3f::new(0.0, 0.0, 0.0), &Vec3f::new(0.0, 1.0, 0.0));
+
+        let mut screen = Screen::new(32, 32);
+        draw_mesh(&mesh, &Mat4x4f::identity(), &camera, &RenderState::new(), &StandardVertexShader, &NormalShader, &mut screen);
+
+        let offset = (16 * 32 + 16) * 3;
+        assert_eq!(&screen.color[offset..offset+3], &[128, 128, 255]);
+    }
+
+    #[test]
+    fn test_reversed_depth_matches_standard() {
+        use crate::resources::create_cube;
+
+        // Cubes at different distances, overlapping on screen
+        let mesh = create_cube();
+        let models = [
+            Mat4x4f::translation(1.0, 0.5, 6.0) * Mat4x4f::rotation_y(0.6),
+            Mat4x4f::translation(0.0, 0.0, 0.0) * Mat4x4f::rotation_x(0.3),
+            Mat4x4f::translation(-0.5, -0.2, -3.0) * Mat4x4f::rotation_y(-0.4) * Mat4x4f::scale_uniform(0.5),
+        ];
+
+        let render = |mut camera: Camera, reverse_draw_order: bool| -> Screen {
+            camera.set_position(&Vec3f::new(0.0, 0.0, -8.0));
+            let mut screen = Screen::with_depth_convention(64, 48, camera.projection().depth_convention());
+            clear_depth(&mut screen);
+
+            let mut order: Vec<&Mat4x4f> = models.iter().collect();
+            if reverse_draw_order {
+                order.reverse();
+            }
+            for model in order {
+                draw_mesh(&mesh, model, &camera, &RenderState::new(), &StandardVertexShader, &NormalShader, &mut screen);
+            }
+            screen
+        };
+
+        let standard = render(Camera::perspective(80.0, 0.1, 1000.0, 0.75), false);
+        let reversed = render(Camera::perspective_reversed_infinite(80.0, 0.1, 0.75), false);
+        let reversed_back_to_front = render(Camera::perspective_reversed_infinite(80.0, 0.1, 0.75), true);
+
+        assert!(standard.color.iter().any(|c| *c != 0));
+        assert_eq!(reversed.depth_convention, DepthConvention::Reversed);
+        assert!(standard.color == reversed.color);
+        assert!(reversed.color == reversed_back_to_front.color);
+
+        // Nearer is bigger now, and nothing goes out past the cleared far plane
+        assert!(reversed.depth.iter().all(|d| *d >= 0.0 && *d <= 1.0));
+        assert!(reversed.depth.iter().any(|d| *d > 0.0));
+    }
+
+    #[test]
+    fn test_singular_transform_draws_nothing() {
+        use crate::resources::create_cube;
+
+        let mesh = create_cube();
+        let mut camera = Camera::perspective(80.0, 0.1, 100.0, 1.0);
+        camera.set_position(&Vec3f::new(0.0, 0.0, -8.0));
+
+        // Squashed flat, the side faces would still cover pixels
+        let mut screen = Screen::new(32, 32);
+        let flat = Mat4x4f::rotation_y(0.5) * Mat4x4f::scale(1.0, 1.0, 0.0);
+        draw_mesh(&mesh, &flat, &camera, &RenderState::new(), &StandardVertexShader, &NormalShader, &mut screen);
+
+        assert!(screen.color.iter().all(|c| *c == 0));
+        assert!(screen.depth.iter().all(|d| *d == 1.0));
+    }
+
+    // Counts how many times the vertex shader runs
+    struct CountingVertexShader {
+        count: std::cell::Cell<usize>,
+    }
+
+    impl VertexShader for CountingVertexShader {
+        type Output = StandardVaryings;
+
+        fn vertex(&self, input: &VertexInput, transforms: &Transforms) -> (Vec4f, StandardVaryings) {
+            self.count.set(self.count.get() + 1);
+            StandardVertexShader.vertex(input, transforms)
+        }
+    }
+
+    #[test]
+    fn test_vertices_are_shaded_once() {
+        use crate::resources::create_cube;
+
+        let mut camera = Camera::perspective(80.0, 0.1, 100.0, 1.0);
+        camera.set_position(&Vec3f::new(2.0, 3.0, -8.0));
+        camera.look_at(&Vec3f::new(0.0, 0.0, 0.0), &Vec3f::new(0.0, 1.0, 0.0));
+        let vs = CountingVertexShader { count: std::cell::Cell::new(0) };
+
+        // Flat shaded, each vertex gets split for every face it's part of,
+        // the same as hard edges would, see test_compute_normals_crease
+        let mut mesh = create_cube();
+        let flat = process_geometry(&mesh, &Mat4x4f::identity(), &camera, &vs, 32, 32);
+        assert_eq!(vs.count.get(), 28);
+        assert!(vs.count.get() < mesh.tris.len());
+
+        // With vertex normals, triangles share their vertices
+        mesh.compute_normals(30.0);
+        vs.count.set(0);
+        let smooth = process_geometry(&mesh, &Mat4x4f::identity(), &camera, &vs, 32, 32);
+        assert_eq!(vs.count.get(), mesh.verts.len());
+        assert!(mesh.verts.len() < mesh.tris.len());
+
+        // Either way, the same triangles come out
+        assert_eq!(flat.len(), 6);
+        assert_eq!(smooth.len(), flat.len());
+        for (a, b) in flat.iter().zip(smooth.iter()) {
+            assert_eq!(a.points, b.points);
+        }
+    }
+
+    #[test]
+    fn test_compute_normals_crease() {
+        use crate::resources::create_cube;
+
+        // Hard edges: every face gets its own copy of its corners, and the
+        // bottom face has two more, as its triangles don't agree on the uvs
+        let mut mesh = create_cube();
+        mesh.compute_normals(30.0);
+        assert_eq!(mesh.verts.len(), 28);
+        let normals = mesh.normals.as_ref().unwrap();
+        assert_eq!(normals[mesh.tris[0]], Vec3f::new(0.0, 0.0, -1.0));
+
+        // Smooth: faces get averaged across the uv seams, so no vertices get
+        // added, and normals point diagonally outwards
+        let mut mesh = create_cube();
+        let num_verts = mesh.verts.len();
+        mesh.compute_normals(180.0);
+        assert_eq!(mesh.verts.len(), num_verts);
+        let normals = mesh.normals.as_ref().unwrap();
+        for (v, n) in mesh.verts.iter().zip(normals.iter()) {
+            let expected = Vec3f::from(v).normalize();
+            assert!(Vec3f::dot(n, &expected) > 0.999);
+        }
+    }
+
+    #[test]
+    fn test_mesh_validation() {
+        let verts = vec![Vec4f::new(0.0, 0.0, 0.0, 1.0); 3];
+        let uvs = vec![Vec2f::new(0.0, 0.0); 3];
+
+        assert!(Mesh::new(verts.clone(), vec![0, 1, 2], uvs.clone()).is_ok());
+        assert!(Mesh::new(verts.clone(), vec![0, 1], uvs.clone()).unwrap_err().contains("whole triangles"));
+        assert!(Mesh::new(verts.clone(), vec![0, 1, 3], uvs.clone()).unwrap_err().contains("Index 3 at 2 out of range"));
+        assert!(Mesh::new(verts.clone(), vec![0, 1, 2], vec![Vec2f::new(0.0, 0.0); 6]).unwrap_err().contains("6 uvs for 3 vertices"));
+
+        // Optional streams get checked once they're there
+        let mut mesh = Mesh::new(verts.clone(), vec![0, 1, 2], uvs.clone()).unwrap();
+        mesh.colors = Some(vec![Color::white(); 2]);
+        assert!(mesh.validate().unwrap_err().contains("2 colors for 3 vertices"));
+
+        mesh.colors = None;
+        mesh.submeshes.push(SubMesh { material: String::from("red"), first_tri: 0, num_tris: 2 });
+        assert!(mesh.validate().unwrap_err().contains("Submesh 'red'"));
+
+        // Per corner uvs need one for each index
+        assert!(Mesh::from_corner_uvs(verts.clone(), vec![0, 1, 2], vec![Vec2f::new(0.0, 0.0); 4]).is_err());
+        assert!(Mesh::from_corner_uvs(verts, vec![0, 1, 5], uvs).is_err());
+    }
+
+    #[test]
+    fn test_weld() {
+        // A quad as two separate triangles, plus a vertex nothing uses
+        let p = |x: f32, y: f32| Vec4f::new(x, y, 0.0, 1.0);
+        let verts = vec![p(0.0, 0.0), p(0.0, 1.0), p(1.0, 1.0), p(0.0, 0.0), p(1.0, 1.0), p(1.0, 0.0), p(5.0, 5.0)];
+        let uvs = verts.iter().map(|v| Vec2f::new(v.x, v.y)).collect();
+        let mut mesh = Mesh::new(verts, vec![0, 1, 2, 3, 4, 5], uvs).unwrap();
+
+        assert_eq!(mesh.weld(), 3);
+        assert_eq!(mesh.verts, vec![p(0.0, 0.0), p(0.0, 1.0), p(1.0, 1.0), p(1.0, 0.0)]);
+        assert_eq!(mesh.tris, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.uvs[3], Vec2f::new(1.0, 0.0));
+        assert!(mesh.validate().is_ok());
+
+        // Vertices that only share their position stay apart
+        mesh.verts.push(p(0.0, 0.0));
+        mesh.uvs.push(Vec2f::new(0.5, 0.5));
+        mesh.colors = Some(vec![Color::white(); 5]);
+        mesh.tris[3] = 4;
+        assert_eq!(mesh.weld(), 0);
+        assert_eq!(mesh.tris, vec![0, 1, 2, 3, 2, 4]);
+
+        // Nor do ones that only differ in color
+        mesh.uvs[3] = Vec2f::new(0.0, 0.0);
+        mesh.colors.as_mut().unwrap()[3] = Color::black();
+        assert_eq!(mesh.weld(), 0);
+        mesh.colors.as_mut().unwrap()[3] = Color::white();
+        assert_eq!(mesh.weld(), 1);
+        assert_eq!(mesh.tris, vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn test_compute_tangents() {
+        use crate::resources::{create_cube, create_plane};
+
+        // Tangent along +u, which runs along x, and the bitangent along +v, which runs along z
+        let mut plane = create_plane(1.0);
+        plane.compute_normals(30.0);
+        plane.compute_tangents();
+        let normal = Vec3f::new(0.0, 1.0, 0.0);
+        for t in plane.tangents.as_ref().unwrap().iter() {
+            assert!(Vec3f::dot(&Vec3f::from(t), &Vec3f::new(1.0, 0.0, 0.0)) > 0.9999);
+            let bitangent = Vec3f::cross(&normal, &Vec3f::from(t)) * t.w;
+            assert!(Vec3f::dot(&bitangent, &Vec3f::new(0.0, 0.0, 1.0)) > 0.9999);
+        }
+
+        // Mirrored along u, the tangent flips around, but the bitangent doesn't
+        for uv in plane.uvs.iter_mut() {
+            uv.x = 1.0 - uv.x;
+        }
+        plane.compute_tangents();
+        for t in plane.tangents.as_ref().unwrap().iter() {
+            assert!(Vec3f::dot(&Vec3f::from(t), &Vec3f::new(-1.0, 0.0, 0.0)) > 0.9999);
+            let bitangent = Vec3f::cross(&normal, &Vec3f::from(t)) * t.w;
+            assert!(Vec3f::dot(&bitangent, &Vec3f::new(0.0, 0.0, 1.0)) > 0.9999);
+        }
+
+        // On every face of the cube, a unit tangent along the normal's plane, with
+        // tangent and bitangent pointing the way u and v increase across the face
+        let mut cube = create_cube();
+        cube.compute_normals(30.0);
+        cube.compute_tangents();
+        let normals = cube.normals.as_ref().unwrap();
+        let tangents = cube.tangents.as_ref().unwrap();
+
+        for i in 0..cube.tris.len() {
+            let tri = i / 3;
+            let p = |k: usize| Vec3f::from(&cube.verts[cube.tris[tri*3 + k]]);
+            let uv = |k: usize| cube.uvs[cube.tris[tri*3 + k]];
+            let (e1, e2) = (p(1) - p(0), p(2) - p(0));
+            let (duv1, duv2) = (uv(1) - uv(0), uv(2) - uv(0));
+            let r = duv1.x * duv2.y - duv2.x * duv1.y;
+            let s_dir = (e1 * duv2.y - e2 * duv1.y) / r;
+            let t_dir = (e2 * duv1.x - e1 * duv2.x) / r;
+
+            let n = normals[cube.tris[i]];
+            let t = tangents[cube.tris[i]];
+            let tangent = Vec3f::from(&t);
+            let bitangent = Vec3f::cross(&n, &tangent) * t.w;
+
+            assert!(f32::abs(tangent.length() - 1.0) < 1e-5);
+            assert!(f32::abs(Vec3f::dot(&tangent, &n)) < 1e-5);
+            assert!(Vec3f::dot(&tangent, &s_dir) > 0.0);
+            assert!(Vec3f::dot(&bitangent, &t_dir) > 0.0);
+        }
+    }
+
+    // Counts how many times each pixel gets shaded, without writing anything
+    struct CoverageShader {
+        width: usize,
+        counts: std::cell::RefCell<Vec<u32>>,
+    }
+
+    impl FragmentShader<()> for CoverageShader {
+        fn fragment(&self, frag: &Fragment<()>) -> Option<Color> {
+            self.counts.borrow_mut()[frag.y * self.width + frag.x] += 1;
+            None
+        }
+    }
+
     #[test]
-    fn test_approx_eq() {
-        for i in -32..32 {
-            let a = i as f32 + 0.0;
-            let b = i as f32 + 0.00000001;
+    fn test_triangle_fan_covers_each_pixel_once() {
+        // An off-center fan whose rim runs all along the screen borders, so
+        // the triangles tile the whole screen and share all their inner edges.
+        // Odd sizes and awkward fractions make sure edges hit pixel centers
+        // and sub-pixel positions in all sorts of ways.
+        let (width, height) = (61, 47);
+        let rim = [
+            (-0.5, -0.5), (-0.17, -0.5), (0.0, -0.5), (0.31, -0.5),
+            (0.5, -0.5), (0.5, -0.09), (0.5, 0.0), (0.5, 0.4),
+            (0.5, 0.5), (0.23, 0.5), (0.0, 0.5), (-0.38, 0.5),
+            (-0.5, 0.5), (-0.5, 0.13), (-0.5, 0.0), (-0.5, -0.29),
+        ];
+        let centers = [(0.0, 0.0), (0.0371, -0.1129), (-0.25, 0.25)];
+
+        for center in centers.iter() {
+            let vertex = |p: &(f32, f32)| ClipVertex::new(Vec4f::new(p.0, p.1, 0.5, 1.0), ());
+
+            let screen_dims = Vec2i::new(width, height);
+            let mut tris = Vec::new();
+            for i in 0..rim.len() {
+                let next = (i + 1) % rim.len();
+                setup_triangle(&vertex(center), &vertex(&rim[i]), &vertex(&rim[next]), &screen_dims, &mut tris);
+            }
+
+            let shader = CoverageShader {
+                width: width as usize,
+                counts: std::cell::RefCell::new(vec![0; (width * height) as usize]),
+            };
 
-            assert!(approx_eq(a, b));
+            let mut screen = Screen::new(width as usize, height as usize);
+            let mut target = screen.target();
+            for tri in tris.iter() {
+                rasterize_triangle(&mut target, tri, &RenderState::new(), &shader);
+            }
+
+            for (i, count) in shader.counts.borrow().iter().enumerate() {
+                assert_eq!(*count, 1, "pixel ({}, {}) covered {} times", i as i32 % width, i as i32 / width, count);
+            }
         }
     }
+
+    #[test]
+    fn test_top_left_rule() {
+        // A pixel center right on the shared diagonal of two triangles,
+        // and on the outer edges of the square they make up
+        let a = Vec2i::new(0, 0);
+        let b = Vec2i::new(2 << SUBPIXEL_BITS, 0);
+        let c = Vec2i::new(2 << SUBPIXEL_BITS, 2 << SUBPIXEL_BITS);
+        let d = Vec2i::new(0, 2 << SUBPIXEL_BITS);
+
+        let covers = |p0: &Vec2i, p1: &Vec2i, p2: &Vec2i, p: &Vec2i| {
+            [EdgeFunction::new(p1, p2), EdgeFunction::new(p2, p0), EdgeFunction::new(p0, p1)]
+                .iter()
+                .all(|e| e.eval(p) + e.bias >= 0)
+        };
+
+        // On the diagonal, only one of the two triangles gets the pixel
+        let p = Vec2i::new(1 << SUBPIXEL_BITS, 1 << SUBPIXEL_BITS);
+        assert!(covers(&a, &b, &c, &p) != covers(&a, &c, &d, &p));
+
+        // Top and left edges are in, bottom and right edges are out
+        assert!(covers(&a, &b, &c, &Vec2i::new(1 << SUBPIXEL_BITS, 0)));
+        assert!(covers(&a, &c, &d, &Vec2i::new(0, 1 << SUBPIXEL_BITS)));
+        assert!(!covers(&a, &b, &c, &Vec2i::new(2 << SUBPIXEL_BITS, 1 << SUBPIXEL_BITS)));
+        assert!(!covers(&a, &c, &d, &Vec2i::new(1 << SUBPIXEL_BITS, 2 << SUBPIXEL_BITS)));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least 1x1")]
+    fn test_empty_screen_rejected() {
+        Screen::new(0, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "tile size")]
+    fn test_zero_tile_size_rejected() {
+        Screen::new(4, 4).tiles(0);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,13 @@
+#![feature(test)] // for benchmark feature
+
+pub mod camera;
+pub mod draw;
+pub mod light;
+pub mod linalg;
+pub mod resources;
+pub mod texture;
+pub mod scene;
+pub mod shader;
+pub mod shadow;
+pub mod tiles;
+mod bench; // Not exactly sure why, but I need this otherwise my benches don't run
@@ -0,0 +1,260 @@
+/*
+    Light sources
+
+    For a point being shaded, each light tells which direction its light
+    comes from, and how much of it arrives. Colors are linear, per channel,
+    and can go above 1 for brighter lights.
+
+    Point and spot lights fall off with the square of the distance, and are
+    windowed so that they fade to exactly nothing at their range. That keeps
+    a light from reaching across the whole scene, without a visible cutoff.
+
+    Spot lights shine at full strength inside their inner cone, fade out
+    smoothly towards the outer cone, and leave everything beyond it dark.
+    Cone angles are measured from the spot's axis, in radians.
+*/
+
+use crate::linalg::*;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Light {
+    Directional {
+        direction: Vec3f, // The way the light travels, like the sun's rays
+        color: Vec3f,
+    },
+    Point {
+        position: Vec3f,
+        color: Vec3f,
+        range: f32,
+    },
+    Spot {
+        position: Vec3f,
+        direction: Vec3f, // The way the spot points
+        color: Vec3f,
+        range: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+}
+
+impl Light {
+    // The unit vector from the point towards the light, and the light arriving at the point
+    pub fn illuminate(&self, point: &Vec3f) -> (Vec3f, Vec3f) {
+        match *self {
+            Light::Directional { direction, color } => {
+                (direction.normalize() * -1.0, color)
+            },
+            Light::Point { position, color, range } => {
+                let (to_light, distance) = direction_to(point, &position);
+                (to_light, color * attenuation(distance, range))
+            },
+            Light::Spot { position, direction, color, range, inner_angle, outer_angle } => {
+                let (to_light, distance) = direction_to(point, &position);
+                let cos_angle = -Vec3f::dot(&to_light, &direction.normalize());
+                let cone = cone_falloff(cos_angle, inner_angle, outer_angle);
+                (to_light, color * (attenuation(distance, range) * cone))
+            },
+        }
+    }
+}
+
+// Unit direction and distance from a to b. A zero vector if they coincide.
+fn direction_to(a: &Vec3f, b: &Vec3f) -> (Vec3f, f32) {
+    let delta = *b - *a;
+    let distance = delta.length();
+
+    if distance > 0.0 {
+        (delta / distance, distance)
+    } else {
+        (delta, 0.0)
+    }
+}
+
+/*
+    Inverse square falloff, windowed to reach zero at range. The 1 added to
+    the squared distance keeps it finite right at the light, where it gives
+    the light's full color.
+*/
+pub fn attenuation(distance: f32, range: f32) -> f32 {
+    if distance >= range {
+        return 0.0;
+    }
+
+    let ratio = distance / range;
+    let window = 1.0 - ratio * ratio * ratio * ratio;
+
+    window * window / (distance * distance + 1.0)
+}
+
+// 1 inside the inner cone, 0 outside the outer cone, smoothstepped in between
+fn cone_falloff(cos_angle: f32, inner_angle: f32, outer_angle: f32) -> f32 {
+    let cos_inner = f32::cos(inner_angle);
+    let cos_outer = f32::cos(outer_angle);
+
+    if cos_angle >= cos_inner {
+        1.0
+    } else if cos_angle <= cos_outer {
+        0.0
+    } else {
+        let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+    use crate::draw::Color;
+    use crate::shader::*;
+    use crate::texture::*;
+
+    const EPS: f32 = 0.00001;
+
+    fn assert_vec_eq(a: &Vec3f, b: &Vec3f) {
+        assert_approx_eq!(a.x, b.x, EPS);
+        assert_approx_eq!(a.y, b.y, EPS);
+        assert_approx_eq!(a.z, b.z, EPS);
+    }
+
+    #[test]
+    fn test_directional_light() {
+        let light = Light::Directional { direction: Vec3f::new(0.0, -2.0, 0.0), color: Vec3f::new(1.0, 0.5, 0.25) };
+
+        // The same everywhere
+        for p in [Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(100.0, -50.0, 3.0)].iter() {
+            let (to_light, radiance) = light.illuminate(p);
+            assert_vec_eq(&to_light, &Vec3f::new(0.0, 1.0, 0.0));
+            assert_vec_eq(&radiance, &Vec3f::new(1.0, 0.5, 0.25));
+        }
+    }
+
+    #[test]
+    fn test_point_light_attenuation() {
+        let light = Light::Point { position: Vec3f::new(0.0, 2.0, 0.0), color: Vec3f::new(1.0, 1.0, 1.0), range: 4.0 };
+
+        let (to_light, radiance) = light.illuminate(&Vec3f::new(0.0, 0.0, 0.0));
+        assert_vec_eq(&to_light, &Vec3f::new(0.0, 1.0, 0.0));
+        assert_approx_eq!(radiance.x, 0.9375 * 0.9375 / 5.0, EPS);
+
+        // Full strength right at the light, falling off with distance, and gone at its range
+        assert_approx_eq!(attenuation(0.0, 4.0), 1.0, EPS);
+        assert!(attenuation(1.0, 4.0) > attenuation(2.0, 4.0));
+        assert!(attenuation(3.9, 4.0) > 0.0);
+        assert_eq!(attenuation(4.0, 4.0), 0.0);
+        assert_eq!(light.illuminate(&Vec3f::new(0.0, 2.0, 5.0)).1, Vec3f::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_spot_light_cone() {
+        let inner = 0.3;
+        let outer = 0.6;
+        let light = Light::Spot {
+            position: Vec3f::new(0.0, 0.0, 0.0),
+            direction: Vec3f::new(0.0, 0.0, 1.0),
+            color: Vec3f::new(1.0, 1.0, 1.0),
+            range: 100.0,
+            inner_angle: inner,
+            outer_angle: outer,
+        };
+
+        // A point at distance 1 from the spot, at the given angle from its axis
+        let radiance_at = |angle: f32| light.illuminate(&Vec3f::new(f32::sin(angle), 0.0, f32::cos(angle))).1.x;
+        let full = attenuation(1.0, 100.0);
+
+        assert_approx_eq!(radiance_at(0.0), full, EPS);
+        assert_approx_eq!(radiance_at(inner), full, EPS);
+        assert_eq!(radiance_at(outer + 0.01), 0.0);
+        assert_eq!(radiance_at(std::f32::consts::PI), 0.0);
+
+        // Halfway between the cones' cosines is where the smoothstep is at half
+        let halfway = f32::acos((f32::cos(inner) + f32::cos(outer)) * 0.5);
+        assert_approx_eq!(radiance_at(halfway), full * 0.5, EPS);
+    }
+
+    #[test]
+    fn test_lit_shader_accumulates_lights() {
+        let tex = Texture::new(1, 1, TextureLayout::RowMajor, vec![Color::rgba(255, 255, 255, 200)]);
+        let from_above = Light::Directional { direction: Vec3f::new(0.0, -1.0, 0.0), color: Vec3f::new(0.5, 0.0, 0.0) };
+        let at_an_angle = Light::Directional { direction: Vec3f::new(0.0, -1.0, 1.0), color: Vec3f::new(0.0, 0.5, 0.0) };
+        let from_below = Light::Directional { direction: Vec3f::new(0.0, 1.0, 0.0), color: Vec3f::new(0.0, 0.0, 1.0) };
+        let lights = [from_above, at_an_angle, from_below];
+
+        // A floor, seen from straight above
+        let mut shader = LitShader::new(&tex, &lights, &Vec3f::new(0.0, 10.0, 0.0));
+        shader.ambient = Vec3f::new(0.1, 0.1, 0.1);
+        shader.specular = 0.0;
+
+        let varyings = StandardVaryings {
+            uv: Vec2f::new(0.5, 0.5),
+            normal: Vec3f::new(0.0, 1.0, 0.0),
+            color: Vec4f::new(1.0, 1.0, 1.0, 1.0),
+            world_pos: Vec3f::new(0.0, 0.0, 0.0),
+            tangent: Vec4f::new(0.0, 0.0, 0.0, 0.0),
+        };
+        let zero = varyings.scale(0.0);
+        let frag = Fragment { x: 0, y: 0, depth: 0.5, varyings: varyings, ddx: zero, ddy: zero };
+
+        // Lambert for each light facing the floor, on top of the ambient, and alpha kept
+        let lambert = 0.1 + 0.5 * f32::cos(std::f32::consts::FRAC_PI_4);
+        let expected = Color::from_vec3(&Vec3f::new(0.6, lambert, 0.1)).with_alpha(200);
+        assert_eq!(shader.fragment(&frag), Some(expected));
+
+        // Blinn-Phong highlight, strongest where the light reflects right at the eye
+        shader.specular = 0.5;
+        let highlight = shader.fragment(&frag).unwrap();
+        assert!(highlight.r > expected.r);
+        assert!(highlight.g > expected.g);
+        assert_eq!(highlight.b, expected.b);
+    }
+
+    #[test]
+    fn test_lit_shader_normal_map() {
+        // Normal maps come in through load_texture, like any other texture
+        let load_normal_map = |n: &Vec3f| -> Texture {
+            let c = Color::from_vec3(&(*n * 0.5 + Vec3f::new(0.5, 0.5, 0.5)));
+            let path = std::env::temp_dir().join(format!("ramjet_rasterizer_normal_map_{}_{}.png", c.r, c.g));
+            image::save_buffer(&path, &[c.r, c.g, c.b], 1, 1, image::RGB(8)).unwrap();
+            let tex = crate::resources::load_texture(path.to_string_lossy().into_owned()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            tex
+        };
+        let flat = load_normal_map(&Vec3f::new(0.0, 0.0, 1.0));
+        let tilted = load_normal_map(&Vec3f::new(0.6, 0.0, 0.8));
+
+        // A floor with its tangent along x, lit from up and along x
+        let tex = Texture::new(1, 1, TextureLayout::RowMajor, vec![Color::white()]);
+        let lights = [Light::Directional { direction: Vec3f::new(-1.0, -1.0, 0.0), color: Vec3f::new(1.0, 1.0, 1.0) }];
+        let mut shader = LitShader::new(&tex, &lights, &Vec3f::new(0.0, 10.0, 0.0));
+        shader.ambient = Vec3f::new(0.0, 0.0, 0.0);
+        shader.specular = 0.0;
+
+        let varyings = StandardVaryings {
+            uv: Vec2f::new(0.5, 0.5),
+            normal: Vec3f::new(0.0, 1.0, 0.0),
+            color: Vec4f::new(1.0, 1.0, 1.0, 1.0),
+            world_pos: Vec3f::new(0.0, 0.0, 0.0),
+            tangent: Vec4f::new(1.0, 0.0, 0.0, -1.0),
+        };
+        let zero = varyings.scale(0.0);
+        let frag = Fragment { x: 0, y: 0, depth: 0.5, varyings: varyings, ddx: zero, ddy: zero };
+
+        let unmapped = shader.fragment(&frag).unwrap();
+        assert_eq!(unmapped.r, 180);
+
+        // A flat normal map changes nothing, save for 8-bit rounding
+        shader.normal_map = Some(&flat);
+        assert!((shader.fragment(&frag).unwrap().r as i32 - unmapped.r as i32).abs() <= 1);
+        assert!(Vec3f::dot(&shading_normal(Some(&flat), &frag), &Vec3f::new(0.0, 1.0, 0.0)) > 0.9999);
+
+        // Tilted towards the tangent, and so towards the light
+        shader.normal_map = Some(&tilted);
+        let n = shading_normal(Some(&tilted), &frag);
+        assert!(Vec3f::dot(&n, &Vec3f::new(0.6, 0.8, 0.0)) > 0.999);
+        assert!(shader.fragment(&frag).unwrap().r > 250);
+
+        // Without tangents, there's nothing to go on
+        let untangented = Fragment { varyings: StandardVaryings { tangent: zero.tangent, ..varyings }, ..frag };
+        assert_vec_eq(&shading_normal(Some(&tilted), &untangented), &Vec3f::new(0.0, 1.0, 0.0));
+    }
+}
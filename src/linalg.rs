@@ -35,6 +35,18 @@ extern crate float_cmp;
 use std::ops::*;
 use float_cmp::{Ulps, ApproxEq};
 
+/*
+    How close to singular a matrix may get before we refuse to invert it.
+
+    The determinant scales with the matrix, so we compare it against the
+    product of the column lengths, which is as large as it can get for
+    those columns. This makes the test about how squashed the matrix is,
+    not how small: a scale of 0.001 is fine, a column that (almost) lies in
+    the span of the others is not. For a 4x4 matrix, only the xyz parts of
+    the first three columns count, so translation doesn't come into it.
+*/
+pub const SINGULAR_TOLERANCE: f32 = 1e-6;
+
 /*--------------------
     Vec2f
 --------------------*/ 
@@ -56,6 +68,10 @@ impl Vec2f {
     pub fn dot(a : Self, b : Self) -> f32 {
         a.x * b.y + a.y * b.y
     }
+
+    pub fn lerp(a: &Self, b: &Self, t: f32) -> Self {
+        *a + (*b - *a) * t
+    }
 }
 
 impl Add for Vec2f {
@@ -192,6 +208,10 @@ impl Vec3f {
     pub fn cross(a : &Vec3f, b : &Vec3f) -> Self {
         Vec3f::new(a.y * b.z - a.z * b.y, a.z * b.x - a.x * b.z, a.x * b.y - a.y * b.x)
     }
+
+    pub fn lerp(a: &Self, b: &Self, t: f32) -> Self {
+        *a + (*b - *a) * t
+    }
 }
 
 impl Add for Vec3f {
@@ -296,6 +316,30 @@ impl Div<f32> for Vec3f {
     }
 }
 
+impl Index<usize> for Vec3f {
+    type Output = f32;
+
+    fn index(&self, index: usize) -> &f32 {
+        match index {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Trying to index non-existing coefficient on Vec3f: {}", index)
+        }
+    }
+}
+
+impl IndexMut<usize> for Vec3f {
+    fn index_mut<'a>(&'a mut self, index: usize) -> &'a mut f32 {
+        match index {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Trying to index non-existing coefficient on Vec3f: {}", index)
+        }
+    }
+}
+
 // Todo: can we do reinterpret cast? We're now cloning...
 // Feels like you'd slide into unsafe transmute_cast territory
 impl From<&Vec4f> for Vec3f {
@@ -327,7 +371,11 @@ impl Vec4f {
     }
 
     pub fn dot(a : Self, b : Self) -> f32 {
-        a.x * b.y + a.y * b.y + a.z * b.z + a.w * b.w
+        a.x * b.x + a.y * b.y + a.z * b.z + a.w * b.w
+    }
+
+    pub fn lerp(a: &Self, b: &Self, t: f32) -> Self {
+        *a + (*b - *a) * t
     }
 
     pub fn norm_by_w(a: &Self) -> Self {
@@ -348,7 +396,7 @@ impl Add for Vec4f {
             x: self.x + other.x,
             y: self.y + other.y,
             z: self.z + other.z,
-            w: self.y + other.w,
+            w: self.w + other.w,
         }
     }
 }
@@ -362,7 +410,7 @@ impl Sub for Vec4f{
             x: self.x - other.x,
             y: self.y - other.y,
             z: self.z - other.z,
-            w: self.y - other.w,
+            w: self.w - other.w,
         }
     }
 }
@@ -542,6 +590,180 @@ impl Mul<Vec2f> for Mat2x2f {
     }
 }
 
+/*--------------------
+    Mat3x3
+--------------------*/ 
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Mat3x3f {
+    pub values: [Vec3f; 3],
+}
+
+impl Mat3x3f {
+    pub fn zero() -> Mat3x3f {
+        Mat3x3f::from_columns(&[
+            Vec3f::new(0.0,0.0,0.0),
+            Vec3f::new(0.0,0.0,0.0),
+            Vec3f::new(0.0,0.0,0.0)
+        ])
+    }
+
+    pub fn identity() -> Mat3x3f {
+        Mat3x3f::from_columns(&[
+            Vec3f::new(1.0,0.0,0.0),
+            Vec3f::new(0.0,1.0,0.0),
+            Vec3f::new(0.0,0.0,1.0)
+        ])
+    }
+
+    // Arguments are given row by row, same as Mat4x4f::new
+    pub fn new(
+        m00: f32, m10:f32, m20: f32,
+        m01: f32, m11:f32, m21: f32,
+        m02: f32, m12:f32, m22: f32) -> Mat3x3f {
+        Mat3x3f::from_columns(&[
+            Vec3f::new(m00, m01, m02),
+            Vec3f::new(m10, m11, m12),
+            Vec3f::new(m20, m21, m22)
+        ])
+    }
+
+    pub fn from_columns(values: &[Vec3f; 3]) -> Mat3x3f {
+        Mat3x3f {
+            values: *values
+        }
+    }
+
+    // The upper-left 3x3 of a 4x4, i.e. a transform without its translation
+    pub fn from_mat4x4(m: &Mat4x4f) -> Mat3x3f {
+        Mat3x3f::from_columns(&[
+            (&m[0]).into(),
+            (&m[1]).into(),
+            (&m[2]).into()
+        ])
+    }
+
+    pub fn to_mat4x4(&self) -> Mat4x4f {
+        let m = self;
+        Mat4x4f::new(
+            m[0][0], m[1][0], m[2][0], 0.0,
+            m[0][1], m[1][1], m[2][1], 0.0,
+            m[0][2], m[1][2], m[2][2], 0.0,
+            0.0, 0.0, 0.0, 1.0
+        )
+    }
+
+    pub fn transpose(&self) -> Mat3x3f {
+        let m = self;
+        Mat3x3f::new(
+            m[0][0], m[0][1], m[0][2],
+            m[1][0], m[1][1], m[1][2],
+            m[2][0], m[2][1], m[2][2]
+        )
+    }
+
+    // The scalar triple product of the columns
+    pub fn determinant(&self) -> f32 {
+        Vec3f::dot(&Vec3f::cross(&self[0], &self[1]), &self[2])
+    }
+
+    pub fn is_singular(&self) -> bool {
+        let scale = self[0].length() * self[1].length() * self[2].length();
+        f32::abs(self.determinant()) <= scale * SINGULAR_TOLERANCE
+    }
+
+    pub fn try_inverse(&self) -> Option<Mat3x3f> {
+        if self.is_singular() {
+            None
+        } else {
+            Some(self.inverse())
+        }
+    }
+
+    // As per Lengyel: the rows of the inverse are cross products of the columns
+    // Doesn't check for singular matrices, which give inf and nan. See try_inverse.
+    pub fn inverse(&self) -> Mat3x3f {
+        let a = &self[0];
+        let b = &self[1];
+        let c = &self[2];
+
+        let r0 = Vec3f::cross(b, c);
+        let r1 = Vec3f::cross(c, a);
+        let r2 = Vec3f::cross(a, b);
+
+        let inv_det = 1.0 / Vec3f::dot(&r2, c);
+
+        Mat3x3f::new(
+            r0.x * inv_det, r0.y * inv_det, r0.z * inv_det,
+            r1.x * inv_det, r1.y * inv_det, r1.z * inv_det,
+            r2.x * inv_det, r2.y * inv_det, r2.z * inv_det
+        )
+    }
+}
+
+impl Index<usize> for Mat3x3f {
+    type Output = Vec3f;
+
+    fn index(&self, index: usize) -> &Vec3f {
+        &self.values[index]
+    }
+}
+
+impl IndexMut<usize> for Mat3x3f {
+    fn index_mut<'a>(&'a mut self, index: usize) -> &'a mut Vec3f {
+        &mut self.values[index]
+    }
+}
+
+// [x,y], x is column, y is row
+impl Index<[usize; 2]> for Mat3x3f {
+    type Output = f32;
+
+    fn index(&self, index: [usize; 2]) -> &f32 {
+        &self.values[index[0]][index[1]]
+    }
+}
+
+impl IndexMut<[usize; 2]> for Mat3x3f {
+    fn index_mut<'a>(&'a mut self, index: [usize; 2]) -> &'a mut f32 {
+        &mut self.values[index[0]][index[1]]
+    }
+}
+
+impl Mul for Mat3x3f {
+    type Output = Mat3x3f;
+
+    fn mul(self, other: Self) -> Self {
+        let mut m = Mat3x3f::zero();
+
+        for col in 0..3 {
+            m[col] = self * other[col];
+        }
+
+        m
+    }
+}
+
+impl Mul<Vec3f> for Mat3x3f {
+    type Output = Vec3f;
+
+    fn mul(self, v: Vec3f) -> Vec3f {
+        Vec3f::new(
+            self[0][0] * v[0] + self[1][0] * v[1] + self[2][0] * v[2],
+            self[0][1] * v[0] + self[1][1] * v[1] + self[2][1] * v[2],
+            self[0][2] * v[0] + self[1][2] * v[1] + self[2][2] * v[2]
+        )
+    }
+}
+
+impl ApproxEq for Mat3x3f {
+    type Flt = f32;
+
+    fn approx_eq(&self, other: &Self, epsilon: <f32 as ApproxEq>::Flt, ulps: <<f32 as ApproxEq>::Flt as Ulps>::U) -> bool {
+        (0..3).all(|col| (0..3).all(|row| self[[col, row]].approx_eq(&other[[col, row]], epsilon, ulps)))
+    }
+}
+
 /*--------------------
     Mat4x4
 --------------------*/ 
@@ -615,10 +837,45 @@ impl Mat4x4f {
         // )
     }
 
+    // Same factorization as inverse below
+    pub fn determinant(&self) -> f32 {
+        let a: Vec3f = (&self[0]).into();
+        let b: Vec3f = (&self[1]).into();
+        let c: Vec3f = (&self[2]).into();
+        let d: Vec3f = (&self[3]).into();
+
+        let x = &self[[0,3]];
+        let y = &self[[1,3]];
+        let z = &self[[2,3]];
+        let w = &self[[3,3]];
+
+        let s = Vec3f::cross(&a, &b);
+        let t = Vec3f::cross(&c, &d);
+        let u = a * y - b * x;
+        let v = c * w - d * z;
+
+        Vec3f::dot(&s, &v) + Vec3f::dot(&t, &u)
+    }
+
+    pub fn is_singular(&self) -> bool {
+        let length = |col: &Vec4f| Vec3f::from(col).length();
+        let scale = length(&self[0]) * length(&self[1]) * length(&self[2]);
+        f32::abs(self.determinant()) <= scale * SINGULAR_TOLERANCE
+    }
+
+    // Returns None for (nearly) singular matrices, see SINGULAR_TOLERANCE
+    pub fn try_inverse(&self) -> Option<Mat4x4f> {
+        if self.is_singular() {
+            None
+        } else {
+            Some(self.inverse())
+        }
+    }
+
     // As per Lengyel's book Foundations of Game Engine Development
     // Inverts any 4x4 using optimal amount of operations
-    // Todo: since we'll be using Transform matrices a lot,
-    // have a specialized function for inverting using 3x3 submatrix.
+    // For transforms, inverse_affine is cheaper.
+    // Doesn't check for singular matrices, which give inf and nan. See try_inverse.
     pub fn inverse(&self) -> Mat4x4f {
         let a: Vec3f = (&self[0]).into();
         let b: Vec3f = (&self[1]).into();
@@ -635,7 +892,6 @@ impl Mat4x4f {
         let mut u = a * y - b * x;
         let mut v = c * w - d * z;
 
-        // Todo: needs a det(m) == 0 check, otherwise we get inf->nan
         let inv_det = 1.0 / (Vec3f::dot(&s, &v) + Vec3f::dot(&t, &u));
 
         // println!("det: {:?}", (Vec3f::dot(s, v) + Vec3f::dot(t, u)));
@@ -658,6 +914,42 @@ impl Mat4x4f {
         )
     }
 
+    /*
+        Inverts a transform made of rotation, scale, shear and translation,
+        i.e. one whose bottom row is [0 0 0 1]. Only the 3x3 part needs a
+        real inverse, the translation then just gets undone through it.
+    */
+    pub fn inverse_affine(&self) -> Mat4x4f {
+        let m_inv = Mat3x3f::from_mat4x4(self).inverse();
+        Mat4x4f::affine_from_inverse_3x3(&m_inv, &Vec3f::from(&self[3]))
+    }
+
+    pub fn try_inverse_affine(&self) -> Option<Mat4x4f> {
+        Mat3x3f::from_mat4x4(self).try_inverse()
+            .map(|m_inv| Mat4x4f::affine_from_inverse_3x3(&m_inv, &Vec3f::from(&self[3])))
+    }
+
+    fn affine_from_inverse_3x3(m_inv: &Mat3x3f, translation: &Vec3f) -> Mat4x4f {
+        let t = *m_inv * *translation;
+
+        let mut m = m_inv.to_mat4x4();
+        m[3] = Vec4f::new(-t.x, -t.y, -t.z, 1.0);
+        m
+    }
+
+    /*
+        For transforming normals. Under non-uniform scale, transforming a
+        normal the way we transform positions would tilt it off its surface.
+        The inverse-transpose of the 3x3 part keeps it perpendicular.
+        Results still need normalizing.
+
+        Returns None if the transform squashes space flat, as then there
+        are no sensible normals to be had.
+    */
+    pub fn normal_matrix(&self) -> Option<Mat3x3f> {
+        Mat3x3f::from_mat4x4(self).try_inverse().map(|m| m.transpose())
+    }
+
     pub fn translation(x: f32, y: f32, z: f32) -> Mat4x4f {
         Mat4x4f::new(
             1.0, 0.0, 0.0, x,
@@ -720,11 +1012,44 @@ impl Mat4x4f {
         proj_mat[1][1] = fov_rad;
         proj_mat[2][2] = far / (far - near);
         proj_mat[2][3] = 1.0;
-        proj_mat[3][2] = far * near / (far - near);
+        proj_mat[3][2] = -far * near / (far - near);
+        proj_mat[3][3] = 0.0;
+
+        proj_mat
+    }
+
+    /*
+        Perspective with reversed depth and no far plane: depth is near / z,
+        which runs from 1 at the near plane down to 0 at infinity. Pair it
+        with a screen using DepthConvention::Reversed.
+    */
+    pub fn projection_reversed_infinite(near: f32, aspect: f32, fov: f32) -> Mat4x4f {
+        let fov_rad: f32 = 1.0 / f32::tan(fov * 0.5 / 180.0 * std::f32::consts::PI);
+
+        let mut proj_mat = Mat4x4f::identity();
+        proj_mat[0][0] = aspect * fov_rad;
+        proj_mat[1][1] = fov_rad;
+        proj_mat[2][2] = 0.0;
+        proj_mat[2][3] = 1.0;
+        proj_mat[3][2] = near;
         proj_mat[3][3] = 0.0;
 
         proj_mat
     }
+
+    /*
+        Maps the box [left, right] x [bottom, top] x [near, far] onto the
+        part of clip space that the rasterizer puts on screen: x and y in
+        [-0.5, 0.5], depth in [0, 1]. W stays 1.
+    */
+    pub fn orthographic(left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32) -> Mat4x4f {
+        Mat4x4f::new(
+            1.0 / (right - left), 0.0, 0.0, -0.5 * (right + left) / (right - left),
+            0.0, 1.0 / (top - bottom), 0.0, -0.5 * (top + bottom) / (top - bottom),
+            0.0, 0.0, 1.0 / (far - near), -near / (far - near),
+            0.0, 0.0, 0.0, 1.0
+        )
+    }
 }
 
 impl Index<usize> for Mat4x4f {
@@ -810,6 +1135,198 @@ impl ApproxEq for Mat4x4f {
     }
 }
 
+/*--------------------
+    Quatf
+--------------------*/ 
+
+/*
+    Unit quaternions, for orientations and rotations.
+
+    Rotations follow rotation_x and rotation_z: a positive angle turns y
+    towards z around x, and x towards y around z. Around y it turns z
+    towards x, which is the opposite of what rotation_y does.
+*/
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Quatf {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl Quatf {
+    pub fn new(x: f32, y: f32, z: f32, w: f32) -> Quatf {
+        Quatf {
+            x: x,
+            y: y,
+            z: z,
+            w: w,
+        }
+    }
+
+    pub fn identity() -> Quatf {
+        Quatf::new(0.0, 0.0, 0.0, 1.0)
+    }
+
+    // Axis should be normalized
+    pub fn from_axis_angle(axis: &Vec3f, radians: f32) -> Quatf {
+        let s = f32::sin(radians * 0.5);
+        Quatf::new(axis.x * s, axis.y * s, axis.z * s, f32::cos(radians * 0.5))
+    }
+
+    // Rotates around z first, then x, then y. Roll, pitch, yaw.
+    pub fn from_euler(x: f32, y: f32, z: f32) -> Quatf {
+        Quatf::from_axis_angle(&Vec3f::new(0.0, 1.0, 0.0), y) *
+        Quatf::from_axis_angle(&Vec3f::new(1.0, 0.0, 0.0), x) *
+        Quatf::from_axis_angle(&Vec3f::new(0.0, 0.0, 1.0), z)
+    }
+
+    pub fn dot(a: &Quatf, b: &Quatf) -> f32 {
+        a.x * b.x + a.y * b.y + a.z * b.z + a.w * b.w
+    }
+
+    pub fn length(&self) -> f32 {
+        f32::sqrt(Quatf::dot(self, self))
+    }
+
+    pub fn normalize(&self) -> Quatf {
+        let len = self.length();
+        Quatf::new(self.x / len, self.y / len, self.z / len, self.w / len)
+    }
+
+    // For unit quaternions, this is the inverse rotation
+    pub fn conjugate(&self) -> Quatf {
+        Quatf::new(-self.x, -self.y, -self.z, self.w)
+    }
+
+    pub fn rotate(&self, v: &Vec3f) -> Vec3f {
+        // v + 2w(q x v) + 2q x (q x v), which saves us building a matrix
+        let q = Vec3f::new(self.x, self.y, self.z);
+        let t = Vec3f::cross(&q, v) * 2.0;
+        *v + t * self.w + Vec3f::cross(&q, &t)
+    }
+
+    pub fn to_mat4x4(&self) -> Mat4x4f {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+
+        Mat4x4f::new(
+            1.0 - 2.0 * (y*y + z*z), 2.0 * (x*y - w*z), 2.0 * (x*z + w*y), 0.0,
+            2.0 * (x*y + w*z), 1.0 - 2.0 * (x*x + z*z), 2.0 * (y*z - w*x), 0.0,
+            2.0 * (x*z - w*y), 2.0 * (y*z + w*x), 1.0 - 2.0 * (x*x + y*y), 0.0,
+            0.0, 0.0, 0.0, 1.0
+        )
+    }
+
+    /*
+        Takes the rotation from the upper 3x3 of a matrix, which should be
+        orthonormal. We solve for the largest component first, as dividing
+        by a small one loses precision. (Shepperd's method)
+    */
+    pub fn from_mat4x4(m: &Mat4x4f) -> Quatf {
+        // m[[column, row]]
+        let (m00, m11, m22) = (m[[0,0]], m[[1,1]], m[[2,2]]);
+        let trace = m00 + m11 + m22;
+
+        let q = if trace > 0.0 {
+            let s = f32::sqrt(trace + 1.0) * 2.0;
+            Quatf::new(
+                (m[[1,2]] - m[[2,1]]) / s,
+                (m[[2,0]] - m[[0,2]]) / s,
+                (m[[0,1]] - m[[1,0]]) / s,
+                0.25 * s)
+        } else if m00 > m11 && m00 > m22 {
+            let s = f32::sqrt(1.0 + m00 - m11 - m22) * 2.0;
+            Quatf::new(
+                0.25 * s,
+                (m[[1,0]] + m[[0,1]]) / s,
+                (m[[2,0]] + m[[0,2]]) / s,
+                (m[[1,2]] - m[[2,1]]) / s)
+        } else if m11 > m22 {
+            let s = f32::sqrt(1.0 + m11 - m00 - m22) * 2.0;
+            Quatf::new(
+                (m[[1,0]] + m[[0,1]]) / s,
+                0.25 * s,
+                (m[[2,1]] + m[[1,2]]) / s,
+                (m[[2,0]] - m[[0,2]]) / s)
+        } else {
+            let s = f32::sqrt(1.0 + m22 - m00 - m11) * 2.0;
+            Quatf::new(
+                (m[[2,0]] + m[[0,2]]) / s,
+                (m[[2,1]] + m[[1,2]]) / s,
+                0.25 * s,
+                (m[[0,1]] - m[[1,0]]) / s)
+        };
+
+        q.normalize()
+    }
+
+    // q and -q are the same rotation. Pick whichever of the two gets us from a to b the short way round.
+    fn closest(a: &Quatf, b: &Quatf) -> Quatf {
+        if Quatf::dot(a, b) < 0.0 {
+            Quatf::new(-b.x, -b.y, -b.z, -b.w)
+        } else {
+            *b
+        }
+    }
+
+    // Cheap, but doesn't rotate at constant speed
+    pub fn nlerp(a: &Quatf, b: &Quatf, t: f32) -> Quatf {
+        let b = Quatf::closest(a, b);
+
+        Quatf::new(
+            a.x + (b.x - a.x) * t,
+            a.y + (b.y - a.y) * t,
+            a.z + (b.z - a.z) * t,
+            a.w + (b.w - a.w) * t).normalize()
+    }
+
+    pub fn slerp(a: &Quatf, b: &Quatf, t: f32) -> Quatf {
+        let b = Quatf::closest(a, b);
+        let cos_theta = Quatf::dot(a, &b);
+
+        // Nearly parallel, where sin(theta) goes to zero. Nlerp is just as good there.
+        if cos_theta > 0.9995 {
+            return Quatf::nlerp(a, &b, t);
+        }
+
+        let theta = f32::acos(cos_theta);
+        let sin_theta = f32::sin(theta);
+        let w_a = f32::sin((1.0 - t) * theta) / sin_theta;
+        let w_b = f32::sin(t * theta) / sin_theta;
+
+        Quatf::new(
+            a.x * w_a + b.x * w_b,
+            a.y * w_a + b.y * w_b,
+            a.z * w_a + b.z * w_b,
+            a.w * w_a + b.w * w_b)
+    }
+}
+
+// Composes rotations like matrices do: (a * b) rotates by b first, then by a
+impl Mul for Quatf {
+    type Output = Quatf;
+
+    fn mul(self, other: Self) -> Self {
+        let (a, b) = (self, other);
+        Quatf::new(
+            a.w * b.x + a.x * b.w + a.y * b.z - a.z * b.y,
+            a.w * b.y - a.x * b.z + a.y * b.w + a.z * b.x,
+            a.w * b.z + a.x * b.y - a.y * b.x + a.z * b.w,
+            a.w * b.w - a.x * b.x - a.y * b.y - a.z * b.z)
+    }
+}
+
+impl ApproxEq for Quatf {
+    type Flt = f32;
+
+    fn approx_eq(&self, other: &Self, epsilon: <f32 as ApproxEq>::Flt, ulps: <<f32 as ApproxEq>::Flt as Ulps>::U) -> bool {
+        self.x.approx_eq(&other.x, epsilon, ulps) &&
+        self.y.approx_eq(&other.y, epsilon, ulps) &&
+        self.z.approx_eq(&other.z, epsilon, ulps) &&
+        self.w.approx_eq(&other.w, epsilon, ulps)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -896,6 +1413,59 @@ mod tests {
         assert_eq!(m * v, Vec4f::new(2.0, 4.0, 4.0, 1.0));
     }
 
+    #[test]
+    fn test_lerp_vec4f() {
+        let a = Vec4f::new(0.0, 1.0, 2.0, 1.0);
+        let b = Vec4f::new(2.0, 3.0, 4.0, 3.0);
+        assert_eq!(Vec4f::lerp(&a, &b, 0.5), Vec4f::new(1.0, 2.0, 3.0, 2.0));
+    }
+
+    #[test]
+    fn test_projection_depth_range() {
+        let near = 0.1;
+        let far = 100.0;
+        let m = Mat4x4f::projection(near, far, 1.0, 90.0);
+
+        let p_near = Vec4f::norm_by_w(&(m * Vec4f::new(0.0, 0.0, near, 1.0)));
+        let p_far = Vec4f::norm_by_w(&(m * Vec4f::new(0.0, 0.0, far, 1.0)));
+
+        assert_approx_eq!(p_near.z, 0.0);
+        assert_approx_eq!(p_far.z, 1.0);
+    }
+
+    #[test]
+    fn test_projection_reversed_infinite_depth_range() {
+        let near = 0.1;
+        let m = Mat4x4f::projection_reversed_infinite(near, 1.0, 90.0);
+
+        let p_near = Vec4f::norm_by_w(&(m * Vec4f::new(0.0, 0.0, near, 1.0)));
+        let p_mid = Vec4f::norm_by_w(&(m * Vec4f::new(0.0, 0.0, 10.0, 1.0)));
+        let p_far = Vec4f::norm_by_w(&(m * Vec4f::new(0.0, 0.0, 1e30, 1.0)));
+
+        assert_approx_eq!(p_near.z, 1.0);
+        assert_approx_eq!(p_mid.z, 0.01);
+        assert_approx_eq!(p_far.z, 0.0);
+
+        // Same x and y as the regular projection
+        let m_std = Mat4x4f::projection(near, 100.0, 1.0, 90.0);
+        let p = Vec4f::new(1.0, -2.0, 5.0, 1.0);
+        let (a, b) = (Vec4f::norm_by_w(&(m * p)), Vec4f::norm_by_w(&(m_std * p)));
+        assert_eq!((a.x, a.y, a.w), (b.x, b.y, b.w));
+    }
+
+    #[test]
+    fn test_orthographic() {
+        let m = Mat4x4f::orthographic(-2.0, 6.0, -1.0, 3.0, 0.5, 10.5);
+
+        let p_min = m * Vec4f::new(-2.0, -1.0, 0.5, 1.0);
+        let p_max = m * Vec4f::new(6.0, 3.0, 10.5, 1.0);
+        let p_center = m * Vec4f::new(2.0, 1.0, 5.5, 1.0);
+
+        assert!(p_min.approx_eq(&Vec4f::new(-0.5, -0.5, 0.0, 1.0), EPS, 4));
+        assert!(p_max.approx_eq(&Vec4f::new(0.5, 0.5, 1.0, 1.0), EPS, 4));
+        assert!(p_center.approx_eq(&Vec4f::new(0.0, 0.0, 0.5, 1.0), EPS, 4));
+    }
+
     #[test]
     fn test_indexers() {
         let mut x = Vec4f::new(0.0,0.0,0.0,1.0);
@@ -906,4 +1476,203 @@ mod tests {
         m[0] = Vec4f::new(0.0,0.0,0.0,1.0);
         m[[0, 1]] = 0.0;
     }
-}
\ No newline at end of file
+
+    const EPS: f32 = 0.00001;
+
+    fn quat_approx_eq(a: &Quatf, b: &Quatf) -> bool {
+        // q and -q are the same rotation
+        a.approx_eq(b, EPS, 4) || a.approx_eq(&Quatf::new(-b.x, -b.y, -b.z, -b.w), EPS, 4)
+    }
+
+    #[test]
+    fn test_quat_axis_angle_matches_rotation_matrices() {
+        let x = Vec3f::new(1.0, 0.0, 0.0);
+        let y = Vec3f::new(0.0, 1.0, 0.0);
+        let z = Vec3f::new(0.0, 0.0, 1.0);
+
+        for i in -8..8 {
+            let angle = i as f32 * 0.4;
+            assert!(Quatf::from_axis_angle(&x, angle).to_mat4x4().approx_eq(&Mat4x4f::rotation_x(angle), EPS, 4));
+            assert!(Quatf::from_axis_angle(&z, angle).to_mat4x4().approx_eq(&Mat4x4f::rotation_z(angle), EPS, 4));
+
+            // rotation_y turns the other way round
+            assert!(Quatf::from_axis_angle(&y, angle).to_mat4x4().approx_eq(&Mat4x4f::rotation_y(-angle), EPS, 4));
+        }
+    }
+
+    #[test]
+    fn test_quat_euler() {
+        let (x, y, z) = (0.3, -1.2, 2.1);
+        let q = Quatf::from_euler(x, y, z);
+        let m = Mat4x4f::rotation_y(-y) * Mat4x4f::rotation_x(x) * Mat4x4f::rotation_z(z);
+
+        assert!(q.to_mat4x4().approx_eq(&m, EPS, 4));
+    }
+
+    #[test]
+    fn test_quat_mul_and_rotate() {
+        let a = Quatf::from_axis_angle(&Vec3f::new(1.0, 2.0, -1.0).normalize(), 0.7);
+        let b = Quatf::from_axis_angle(&Vec3f::new(-3.0, 0.5, 2.0).normalize(), 2.3);
+        let m = a.to_mat4x4() * b.to_mat4x4();
+
+        assert!((a * b).to_mat4x4().approx_eq(&m, EPS, 4));
+
+        let v = Vec3f::new(0.3, -2.0, 5.0);
+        let rotated = (a * b).rotate(&v);
+        let expected = m * Vec4f::new(v.x, v.y, v.z, 0.0);
+        assert!(Vec4f::new(rotated.x, rotated.y, rotated.z, 0.0).approx_eq(&expected, EPS, 4));
+
+        // Undoing a rotation gets us back where we started
+        assert!(((a * b) * (a * b).conjugate()).approx_eq(&Quatf::identity(), EPS, 4));
+    }
+
+    #[test]
+    fn test_quat_normalize() {
+        let q = Quatf::new(1.0, 2.0, 3.0, 4.0).normalize();
+        assert_approx_eq!(q.length(), 1.0);
+    }
+
+    #[test]
+    fn test_quat_matrix_roundtrip() {
+        // Hit each of the branches in from_mat4x4, including 180 degree turns
+        let axes = [
+            Vec3f::new(1.0, 0.0, 0.0),
+            Vec3f::new(0.0, 1.0, 0.0),
+            Vec3f::new(0.0, 0.0, 1.0),
+            Vec3f::new(1.0, -1.0, 2.0).normalize(),
+        ];
+
+        for axis in axes.iter() {
+            for angle in [0.0, 0.5, 2.0, std::f32::consts::PI].iter() {
+                let q = Quatf::from_axis_angle(axis, *angle);
+                let q_back = Quatf::from_mat4x4(&q.to_mat4x4());
+                assert!(quat_approx_eq(&q, &q_back), "{:?} != {:?}", q, q_back);
+            }
+        }
+    }
+
+    #[test]
+    fn test_quat_slerp() {
+        let axis = Vec3f::new(0.0, 0.0, 1.0);
+        let a = Quatf::from_axis_angle(&axis, 0.2);
+        let b = Quatf::from_axis_angle(&axis, 1.8);
+
+        assert!(quat_approx_eq(&Quatf::slerp(&a, &b, 0.0), &a));
+        assert!(quat_approx_eq(&Quatf::slerp(&a, &b, 1.0), &b));
+
+        // Constant angular speed along the way
+        for i in 1..4 {
+            let t = i as f32 * 0.25;
+            let expected = Quatf::from_axis_angle(&axis, 0.2 + 1.6 * t);
+            assert!(quat_approx_eq(&Quatf::slerp(&a, &b, t), &expected));
+        }
+
+        // Nlerp only agrees halfway, by symmetry
+        assert!(quat_approx_eq(&Quatf::nlerp(&a, &b, 0.5), &Quatf::from_axis_angle(&axis, 1.0)));
+
+        // Going from 170 to -170 degrees takes the short way, through 180
+        let a = Quatf::from_axis_angle(&axis, 170.0f32.to_radians());
+        let b = Quatf::from_axis_angle(&axis, -170.0f32.to_radians());
+        let halfway = Quatf::slerp(&a, &b, 0.5);
+        assert!(quat_approx_eq(&halfway, &Quatf::from_axis_angle(&axis, std::f32::consts::PI)));
+    }
+
+    #[test]
+    fn test_mat3x3_inverse() {
+        let m = Mat3x3f::new(
+            2.0, 1.0, 0.0,
+            0.0, 3.0, 1.0,
+            1.0, 0.0, 4.0,
+        );
+
+        assert_approx_eq!(m.determinant(), 25.0);
+        assert_approx_eq!(m.transpose().determinant(), 25.0);
+        assert!((m.inverse() * m).approx_eq(&Mat3x3f::identity(), EPS, 4));
+        assert!((m * m.inverse()).approx_eq(&Mat3x3f::identity(), EPS, 4));
+    }
+
+    #[test]
+    fn test_mat3x3_mul() {
+        let a = Mat3x3f::from_mat4x4(&Mat4x4f::rotation_x(0.4));
+        let b = Mat3x3f::from_mat4x4(&Mat4x4f::scale(2.0, 3.0, 4.0));
+        let expected = Mat3x3f::from_mat4x4(&(Mat4x4f::rotation_x(0.4) * Mat4x4f::scale(2.0, 3.0, 4.0)));
+
+        assert!((a * b).approx_eq(&expected, EPS, 4));
+        assert_eq!((a * b).to_mat4x4(), Mat4x4f::rotation_x(0.4) * Mat4x4f::scale(2.0, 3.0, 4.0));
+        assert_eq!(b * Vec3f::new(1.0, 1.0, 1.0), Vec3f::new(2.0, 3.0, 4.0));
+    }
+
+    #[test]
+    fn test_inverse_affine() {
+        let m =
+            Mat4x4f::translation(1.0, -2.0, 3.0) *
+            Mat4x4f::rotation_y(0.7) *
+            Mat4x4f::scale(0.5, 2.0, 3.0) *
+            Mat4x4f::rotation_z(-1.1);
+
+        assert!((m.inverse_affine() * m).approx_eq(&Mat4x4f::identity(), EPS, 4));
+        assert!(m.inverse_affine().approx_eq(&m.inverse(), EPS, 4));
+    }
+
+    #[test]
+    fn test_normal_matrix() {
+        // A plane tilted 45 degrees, squashed along x
+        let m = Mat4x4f::scale(0.25, 1.0, 1.0);
+        let tangent = Vec3f::new(1.0, 1.0, 0.0);
+        let normal = Vec3f::new(1.0, -1.0, 0.0);
+
+        // Transformed like a position, the normal no longer sits at right angles
+        let tangent_m = Vec3f::from(&(m * Vec4f::new(tangent.x, tangent.y, tangent.z, 0.0)));
+        let normal_m = Vec3f::from(&(m * Vec4f::new(normal.x, normal.y, normal.z, 0.0)));
+        assert!(Vec3f::dot(&tangent_m, &normal_m).abs() > 0.1);
+
+        let normal_n = m.normal_matrix().unwrap() * normal;
+        assert_approx_eq!(Vec3f::dot(&tangent_m, &normal_n), 0.0);
+
+        // Rotations leave normals as they are
+        let r = Mat4x4f::rotation_x(0.8) * Mat4x4f::rotation_z(0.3);
+        assert!(r.normal_matrix().unwrap().approx_eq(&Mat3x3f::from_mat4x4(&r), EPS, 4));
+    }
+
+    #[test]
+    fn test_determinant() {
+        assert_approx_eq!(Mat4x4f::identity().determinant(), 1.0);
+        assert_approx_eq!(Mat4x4f::scale(2.0, 3.0, 4.0).determinant(), 24.0);
+        assert_approx_eq!((Mat4x4f::rotation_y(0.3) * Mat4x4f::translation(5.0, 1.0, 2.0)).determinant(), 1.0);
+        assert_approx_eq!(Mat4x4f::projection(0.1, 100.0, 1.0, 90.0).determinant(), 0.1001001);
+
+        // Agrees with the product of the determinants
+        let a = Mat4x4f::rotation_x(1.1) * Mat4x4f::scale(0.5, 2.0, 1.5);
+        let b = Mat4x4f::translation(1.0, 2.0, 3.0) * Mat4x4f::scale_uniform(3.0);
+        assert_approx_eq!((a * b).determinant(), a.determinant() * b.determinant(), 0.0001);
+    }
+
+    #[test]
+    fn test_try_inverse() {
+        let m = Mat4x4f::translation(1.0, 2.0, 3.0) * Mat4x4f::rotation_z(0.5) * Mat4x4f::scale(2.0, 3.0, 4.0);
+        assert!((m.try_inverse().unwrap() * m).approx_eq(&Mat4x4f::identity(), EPS, 4));
+        assert!((m.try_inverse_affine().unwrap() * m).approx_eq(&Mat4x4f::identity(), EPS, 4));
+
+        // Flattened on one axis
+        let flat = Mat4x4f::rotation_y(0.5) * Mat4x4f::scale(1.0, 0.0, 1.0);
+        assert_eq!(flat.try_inverse(), None);
+        assert_eq!(flat.try_inverse_affine(), None);
+        assert_eq!(flat.normal_matrix(), None);
+        assert_eq!(Mat4x4f::zero().try_inverse(), None);
+
+        // Two columns that point almost the same way
+        let mut nearly = Mat4x4f::identity();
+        nearly[1] = Vec4f::new(1.0, 1e-7, 0.0, 0.0);
+        assert_eq!(nearly.try_inverse(), None);
+
+        // Tiny, but perfectly well conditioned
+        let tiny = Mat4x4f::scale_uniform(0.001);
+        assert!((tiny.try_inverse().unwrap() * tiny).approx_eq(&Mat4x4f::identity(), EPS, 4));
+
+        // Far away, which says nothing about its shape
+        let far = Mat4x4f::translation(1e6, -2e6, 1e6) * Mat4x4f::rotation_y(0.5) * Mat4x4f::rotation_x(0.3);
+        assert!(!far.is_singular());
+        assert!(far.try_inverse().is_some());
+        assert!(far.try_inverse_affine().is_some());
+    }
+}
@@ -1,5 +1,3 @@
-#![feature(test)] // for benchmark feature
-
 extern crate sdl2;
 extern crate gl;
 
@@ -10,18 +8,12 @@ use sdl2::keyboard::Keycode;
 use sdl2::render::{TextureCreator};
 use std::{thread, time};
 
-pub mod draw;
-pub mod linalg;
-pub mod resources;
-mod bench; // Not exactly sure why, but I need this otherwise my benches don't run
-
-use linalg::*;
-use draw::*;
-use resources::*;
+use ramjet_rasterizer::draw::*;
+use ramjet_rasterizer::scene::*;
 
 /*
-    Single-threaded software rendering loop that pipes the resulting color buffer
-    into SDL2
+    Software rendering loop that pipes the resulting color buffer into SDL2.
+    Scenes draw through the tiled rasterizer, spread over all cores.
 */
 
 fn main() {
@@ -62,26 +54,16 @@ fn start_renderloop() -> Result<(), String> {
 
     // Create our cpu-side screen buffer
     let mut screen = Screen::new(WIDTH as usize, HEIGHT as usize);
+    screen.set_multisample(Multisample::X4);
 
-    // Load our cube mesh
-    let mesh = create_cube();
-
-    // Camera projection matrix
-    let near: f32 = 0.1;
-    let far: f32 = 1000.0;
-    let fov: f32 = 80.0;
-    let aspect: f32 =  HEIGHT as f32 / WIDTH as f32;
-    let cam_proj = Mat4x4f::projection(near, far, aspect, fov);
+    // Set up our scene of spinning cubes
+    let mut scene = Scene::new(WIDTH as usize, HEIGHT as usize)?;
 
     // Clear screen before doing anything
     canvas.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
     canvas.clear();
     canvas.present();
 
-    // Load our textures
-    let tex_checker = load_texture(String::from("resources/checker.png")).unwrap();
-    let tex_sprite = load_texture(String::from("resources/test.png")).unwrap();
-
     let mut frame : u32 = 0;
     let mut time = 0.0;
 
@@ -103,38 +85,7 @@ fn start_renderloop() -> Result<(), String> {
 
         // Rendering
 
-        // Clear our buffer
-        draw::clear_color(&mut screen);
-        draw::clear_depth(&mut screen);
-
-        // Cam setup
-        let cam = Mat4x4f::translation(0.0, 0.0, -8.0);
-        let cam_inv = cam.inverse();
-
-        // Let's draw some cubes
-
-        // rotate and translate it in world space
-        let obj1_mat = 
-            Mat4x4f::translation(0.0, f32::sin(time * 1.0) * 1.0, 0.0) *
-            Mat4x4f::rotation_y(f32::sin(time * 3.0) * 1.0) *
-            Mat4x4f::rotation_x(f32::sin(time * 1.333) * 1.0);
-
-        let obj2_mat = 
-            Mat4x4f::translation(f32::sin(time * 1.3221) * 2.0, 0.0, 0.0) *
-            Mat4x4f::rotation_y(f32::sin(time * 2.0) * 1.0) *
-            Mat4x4f::rotation_x(f32::sin(time * 1.7672) * 1.0);
-
-        let obj3_mat = 
-            Mat4x4f::scale_uniform(0.5) *
-            Mat4x4f::translation(f32::cos(time * 0.5) * 3.0, f32::sin(time * 1.3221) * 3.0, f32::sin(time * 1.3221) * 3.0) *
-            Mat4x4f::rotation_y(f32::cos(time * 3.1) * 1.0) *
-            Mat4x4f::rotation_x(f32::sin(time * -1.0672) * 1.0);
-        
-        // let obj1_mat = Mat4x4f::identity();
-        
-        draw_mesh(&mesh, &tex_sprite, &obj1_mat, &cam_inv, &cam_proj, &mut screen);
-        draw_mesh(&mesh, &tex_checker, &obj2_mat, &cam_inv, &cam_proj, &mut screen);
-        draw_mesh(&mesh, &tex_checker, &obj3_mat, &cam_inv, &cam_proj, &mut screen);
+        scene.render(&mut screen, time);
 
         // Copy screenbuffer to SDL texture
         texture.with_lock(None, |buffer: &mut [u8], _pitch: usize| {
@@ -1,27 +1,236 @@
 extern crate image;
 use image::*;
 
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
 use crate::draw::*;
 use crate::linalg::*;
+use crate::texture::*;
 
-pub fn load_texture(path: String) -> Result<Vec<Color>,String> {
+pub fn load_texture(path: String) -> Result<Texture,String> {
     let img = image::open(path).map_err(|e| e.to_string())?;
 
     let dims = img.dimensions();
     println!("image dimensions: {:?}", dims);
 
-    let mut tex: Vec<Color> = Vec::with_capacity((dims.0 * dims.1) as usize);
+    let mut pixels: Vec<Color> = Vec::with_capacity((dims.0 * dims.1) as usize);
 
-    for x in 0..dims.0 {
-        for y in 0..dims.1 {
+    for y in 0..dims.1 {
+        for x in 0..dims.0 {
             let c = img.get_pixel(x, y);
-            tex.push(Color::new(c[0], c[1], c[2]));
+            pixels.push(Color::rgba(c[0], c[1], c[2], c[3]));
         }
     }
 
+    let mut tex = Texture::new(dims.0 as usize, dims.1 as usize, TextureLayout::RowMajor, pixels);
+    tex.generate_mips();
+
     Ok(tex)
 }
 
+/*
+    Wavefront OBJ loading
+
+    Supports v, vt, vn and f records, polygonal faces (fanned out into
+    triangles, so they should be convex), negative (relative) indices, and
+    mtllib/usemtl material references. Other records are skipped.
+
+    OBJ is right-handed, we're left-handed, so we mirror z on the way in.
+    That also turns OBJ's counter-clockwise front faces into the clockwise
+    ones our backface culling expects.
+
+    OBJ indexes positions, texture coordinates and normals separately,
+    while our mesh has one index per vertex, so each distinct combination
+    of the three becomes its own vertex. Faces either all have normals, or
+    none of them do.
+*/
+
+pub fn load_obj(path: String) -> Result<Mesh, String> {
+    let source = fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    parse_obj(&source).map_err(|e| format!("{}:{}", path, e))
+}
+
+pub fn parse_obj(source: &str) -> Result<Mesh, String> {
+    let mut positions: Vec<Vec4f> = Vec::new();
+    let mut tex_coords: Vec<Vec2f> = Vec::new();
+    let mut normals: Vec<Vec3f> = Vec::new();
+
+    let mut corners: Vec<(usize, Option<usize>, Option<usize>)> = Vec::new(); // position, texture coordinate, normal
+    let mut faces_have_normals: Option<bool> = None;
+    let mut material_libs: Vec<String> = Vec::new();
+    let mut submeshes: Vec<SubMesh> = Vec::new();
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_nr = line_idx + 1;
+
+        // Strip comments
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) => k,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => {
+                let v = parse_floats(&args, 3, 4, line_nr, keyword)?;
+                positions.push(Vec4f::new(v[0], v[1], -v[2], 1.0));
+            },
+            "vt" => {
+                let v = parse_floats(&args, 1, 3, line_nr, keyword)?;
+                tex_coords.push(Vec2f::new(v[0], if v.len() > 1 { v[1] } else { 0.0 }));
+            },
+            "vn" => {
+                let v = parse_floats(&args, 3, 3, line_nr, keyword)?;
+                normals.push(Vec3f::new(v[0], v[1], -v[2]));
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(format!("{}: face needs at least 3 vertices, got {}", line_nr, args.len()));
+                }
+
+                let mut face: Vec<(usize, Option<usize>, Option<usize>)> = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    let (v, vt, vn) = parse_face_vertex(arg, line_nr)?;
+
+                    let v = resolve_index(v, positions.len(), line_nr, "vertex")?;
+                    let vt = match vt {
+                        Some(vt) => Some(resolve_index(vt, tex_coords.len(), line_nr, "texture coordinate")?),
+                        None => None,
+                    };
+                    let vn = match vn {
+                        Some(vn) => Some(resolve_index(vn, normals.len(), line_nr, "normal")?),
+                        None => None,
+                    };
+
+                    match faces_have_normals {
+                        Some(has) if has != vn.is_some() => {
+                            return Err(format!("{}: mixing faces with and without normals is not supported", line_nr));
+                        },
+                        _ => faces_have_normals = Some(vn.is_some()),
+                    }
+
+                    face.push((v, vt, vn));
+                }
+
+                for i in 1..face.len()-1 {
+                    corners.extend_from_slice(&[face[0], face[i], face[i+1]]);
+                }
+
+                if let Some(submesh) = submeshes.last_mut() {
+                    submesh.num_tris += face.len() - 2;
+                }
+            },
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err(format!("{}: mtllib without a file name", line_nr));
+                }
+                material_libs.extend(args.iter().map(|s| s.to_string()));
+            },
+            "usemtl" => {
+                if args.len() != 1 {
+                    return Err(format!("{}: usemtl expects a single material name", line_nr));
+                }
+                submeshes.push(SubMesh {
+                    material: args[0].to_string(),
+                    first_tri: corners.len() / 3,
+                    num_tris: 0,
+                });
+            },
+            _ => {},
+        }
+    }
+
+    // Give each distinct combination its own vertex
+    let mut vertex_ids: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+    let mut verts: Vec<Vec4f> = Vec::new();
+    let mut uvs: Vec<Vec2f> = Vec::new();
+    let mut vert_normals: Vec<Vec3f> = Vec::new();
+    let mut tris: Vec<usize> = Vec::with_capacity(corners.len());
+
+    for corner in corners.iter() {
+        let id = *vertex_ids.entry(*corner).or_insert_with(|| {
+            let (v, vt, vn) = *corner;
+            verts.push(positions[v]);
+            uvs.push(vt.map_or(Vec2f::new(0.0, 0.0), |vt| tex_coords[vt]));
+            if let Some(vn) = vn {
+                vert_normals.push(normals[vn].normalize());
+            }
+            verts.len() - 1
+        });
+        tris.push(id);
+    }
+
+    let mut mesh = Mesh::new(verts, tris, uvs)?;
+    if faces_have_normals == Some(true) {
+        mesh.normals = Some(vert_normals);
+    }
+    mesh.material_libs = material_libs;
+    mesh.submeshes = submeshes;
+
+    Ok(mesh)
+}
+
+fn parse_floats(args: &[&str], min: usize, max: usize, line_nr: usize, keyword: &str) -> Result<Vec<f32>, String> {
+    if args.len() < min || args.len() > max {
+        return Err(format!("{}: {} expects {} to {} values, got {}", line_nr, keyword, min, max, args.len()));
+    }
+
+    args.iter()
+        .map(|s| s.parse::<f32>().map_err(|_| format!("{}: invalid number '{}' in {} record", line_nr, s, keyword)))
+        .collect()
+}
+
+// Parses v, v/vt, v//vn or v/vt/vn
+fn parse_face_vertex(arg: &str, line_nr: usize) -> Result<(i64, Option<i64>, Option<i64>), String> {
+    let parse = |s: &str| s.parse::<i64>().map_err(|_| format!("{}: invalid index '{}' in face vertex '{}'", line_nr, s, arg));
+
+    let parts: Vec<&str> = arg.split('/').collect();
+    if parts.len() > 3 || parts[0].is_empty() {
+        return Err(format!("{}: malformed face vertex '{}'", line_nr, arg));
+    }
+
+    let v = parse(parts[0])?;
+    let vt = match parts.get(1) {
+        Some(s) if !s.is_empty() => Some(parse(s)?),
+        _ => None,
+    };
+    let vn = match parts.get(2) {
+        Some(s) if !s.is_empty() => Some(parse(s)?),
+        _ => None,
+    };
+
+    Ok((v, vt, vn))
+}
+
+// OBJ indices are 1-based, negative ones count back from the latest element
+fn resolve_index(index: i64, count: usize, line_nr: usize, kind: &str) -> Result<usize, String> {
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("{}: {} index {} out of range, {} defined so far", line_nr, kind, index, count));
+    }
+
+    Ok(resolved as usize)
+}
+
+// Write the screen's color buffer out as an RGB PNG
+pub fn save_png(screen: &Screen, path: &Path) -> Result<(), String> {
+    image::save_buffer(
+        path,
+        &screen.color,
+        screen.width as u32,
+        screen.height as u32,
+        image::RGB(8)).map_err(|e| e.to_string())
+}
+
 pub fn create_test_triangle() -> Mesh {
     // vert buffer
     let verts = vec!(
@@ -40,7 +249,7 @@ pub fn create_test_triangle() -> Mesh {
         Vec2f::new(0.0, 0.0), Vec2f::new(0.0, 1.0), Vec2f::new(1.0, 1.0), 
     );
 
-    Mesh::new(verts, tris, uvs)
+    Mesh::new(verts, tris, uvs).unwrap()
 }
 
 pub fn create_cube() -> Mesh {
@@ -83,6 +292,7 @@ pub fn create_cube() -> Mesh {
         3, 7, 0
     );
 
+    // uvs per corner, in the same order as the index buffer
     let uvs = vec!(
         // front
         Vec2f::new(0.0, 0.0), Vec2f::new(0.0, 1.0), Vec2f::new(1.0, 1.0), 
@@ -109,5 +319,723 @@ pub fn create_cube() -> Mesh {
         Vec2f::new(1.0, 0.0), Vec2f::new(1.0, 1.0), Vec2f::new(0.0, 0.0),
     );
 
-    Mesh::new(verts, tris, uvs)
-}
\ No newline at end of file
+    Mesh::from_corner_uvs(verts, tris, uvs).unwrap()
+}
+
+// A square in the xz plane, facing up, with the texture repeated once per unit
+pub fn create_plane(half_size: f32) -> Mesh {
+    let s = half_size;
+
+    // vert buffer
+    let verts = vec!(
+        Vec4f::new(-s, 0.0, -s, 1.0),
+        Vec4f::new(-s, 0.0,  s, 1.0),
+        Vec4f::new( s, 0.0,  s, 1.0),
+        Vec4f::new( s, 0.0, -s, 1.0)
+    );
+
+    // index buffer, wound like the top of the cube
+    let tris = vec!(
+        0, 1, 2,
+        0, 2, 3
+    );
+
+    let t = half_size * 2.0;
+    let uvs = vec!(
+        Vec2f::new(0.0, 0.0),
+        Vec2f::new(0.0, t),
+        Vec2f::new(t, t),
+        Vec2f::new(t, 0.0)
+    );
+
+    Mesh::new(verts, tris, uvs).unwrap()
+}
+
+/*
+    Procedural meshes
+
+    All of these are centered on the origin, with y up, and come with
+    per-vertex normals and uvs. Their triangles face outwards, the way
+    backface culling expects, and their uvs run left to right and bottom
+    to top as seen from outside, so textures don't come out mirrored.
+
+    Shapes around the y axis start and end their u at the back (+z), where
+    the seam gets a column of vertices for either side. Vertices at a pole
+    get one copy per segment, each with the u of the middle of its segment,
+    and with the triangles that would come to nothing there left out.
+*/
+
+// Collects the vertices and triangles of a generated mesh
+struct MeshBuilder {
+    verts: Vec<Vec4f>,
+    uvs: Vec<Vec2f>,
+    normals: Vec<Vec3f>,
+    tris: Vec<usize>,
+}
+
+// A row of vertices, segments + 1 of them, or segments at a pole
+#[derive(Copy, Clone)]
+struct Row {
+    first: usize,
+    pole: bool,
+}
+
+impl MeshBuilder {
+    fn new() -> MeshBuilder {
+        MeshBuilder {
+            verts: Vec::new(),
+            uvs: Vec::new(),
+            normals: Vec::new(),
+            tris: Vec::new(),
+        }
+    }
+
+    fn vertex(&mut self, position: Vec3f, uv: Vec2f, normal: Vec3f) -> usize {
+        self.verts.push(Vec4f::new(position.x, position.y, position.z, 1.0));
+        self.uvs.push(uv);
+        self.normals.push(normal);
+        self.verts.len() - 1
+    }
+
+    /*
+        A row around the y axis at height v in the texture. The function
+        gets the unit vector out from the axis, and gives the position and
+        normal of the vertex there.
+    */
+    fn ring<F>(&mut self, segments: usize, v: f32, pole: bool, point: F) -> Row
+        where F: Fn(Vec3f) -> (Vec3f, Vec3f) {
+        let first = self.verts.len();
+        let count = if pole { segments } else { segments + 1 };
+
+        for i in 0..count {
+            let u = if pole { (i as f32 + 0.5) / segments as f32 } else { i as f32 / segments as f32 };
+            let (position, normal) = point(around_y(if pole { u } else { (i % segments) as f32 / segments as f32 }));
+            self.vertex(position, Vec2f::new(u, v), normal);
+        }
+
+        Row { first: first, pole: pole }
+    }
+
+    // Quads between two rows, the upper one further along v
+    fn strip(&mut self, lower: Row, upper: Row, segments: usize) {
+        for i in 0..segments {
+            let a = lower.first + i;
+            let b = upper.first + i;
+
+            if lower.pole {
+                self.tris.extend_from_slice(&[a, b, b + 1]);
+            } else if upper.pole {
+                self.tris.extend_from_slice(&[a, b, a + 1]);
+            } else {
+                self.tris.extend_from_slice(&[a, b, b + 1, a, b + 1, a + 1]);
+            }
+        }
+    }
+
+    // A disc closing off a ring at height y, facing up or down
+    fn cap(&mut self, y: f32, radius: f32, segments: usize, up: bool) {
+        let normal = Vec3f::new(0.0, if up { 1.0 } else { -1.0 }, 0.0);
+
+        // Seen from below, +x is on the left
+        let uv = |x: f32, z: f32| Vec2f::new(0.5 + if up { x } else { -x } * 0.5, 0.5 + z * 0.5);
+
+        let center = self.vertex(Vec3f::new(0.0, y, 0.0), uv(0.0, 0.0), normal);
+
+        for i in 0..segments {
+            let out = around_y(i as f32 / segments as f32);
+            self.vertex(out * radius + Vec3f::new(0.0, y, 0.0), uv(out.x, out.z), normal);
+        }
+
+        for i in 0..segments {
+            let a = center + 1 + i;
+            let b = center + 1 + (i + 1) % segments;
+
+            if up {
+                self.tris.extend_from_slice(&[center, b, a]);
+            } else {
+                self.tris.extend_from_slice(&[center, a, b]);
+            }
+        }
+    }
+
+    fn build(self) -> Mesh {
+        let mut mesh = Mesh::new(self.verts, self.tris, self.uvs).unwrap();
+        mesh.normals = Some(self.normals);
+        mesh
+    }
+}
+
+/*
+    Unit vector out from the y axis, a fraction t of the way around from the
+    back. The seam's vertices at either end get theirs from t = 0, as sin(-pi)
+    and sin(pi) round differently, and the two sides should meet exactly.
+*/
+fn around_y(t: f32) -> Vec3f {
+    let angle = (t * 2.0 - 1.0) * std::f32::consts::PI;
+    Vec3f::new(f32::sin(angle), 0.0, -f32::cos(angle))
+}
+
+// Exactly 0 at the poles, where cos(pi / 2) doesn't quite get there
+fn latitude_cos(latitude: f32, pole: bool) -> f32 {
+    if pole { 0.0 } else { f32::cos(latitude) }
+}
+
+// A square in the xz plane, facing up, split into subdivisions x subdivisions quads. Textured like create_plane.
+pub fn create_subdivided_plane(half_size: f32, subdivisions: usize) -> Mesh {
+    assert!(subdivisions >= 1);
+
+    let mut builder = MeshBuilder::new();
+    let n = subdivisions;
+    let mut rows = Vec::with_capacity(n + 1);
+
+    for j in 0..=n {
+        let first = builder.verts.len();
+        let v = j as f32 / n as f32;
+
+        for i in 0..=n {
+            let u = i as f32 / n as f32;
+            let position = Vec3f::new((u * 2.0 - 1.0) * half_size, 0.0, (v * 2.0 - 1.0) * half_size);
+            builder.vertex(position, Vec2f::new(u, v) * (half_size * 2.0), Vec3f::new(0.0, 1.0, 0.0));
+        }
+
+        rows.push(Row { first: first, pole: false });
+    }
+
+    for j in 0..n {
+        builder.strip(rows[j], rows[j + 1], n);
+    }
+
+    builder.build()
+}
+
+// Segments around the y axis, and rings from pole to pole
+pub fn create_uv_sphere(radius: f32, segments: usize, rings: usize) -> Mesh {
+    assert!(segments >= 3 && rings >= 2);
+
+    let mut builder = MeshBuilder::new();
+    let rows: Vec<Row> = (0..=rings).map(|j| {
+        let v = j as f32 / rings as f32;
+        let latitude = (v - 0.5) * std::f32::consts::PI;
+
+        let pole = j == 0 || j == rings;
+
+        builder.ring(segments, v, pole, |out| {
+            let normal = out * latitude_cos(latitude, pole) + Vec3f::new(0.0, f32::sin(latitude), 0.0);
+            (normal * radius, normal)
+        })
+    }).collect();
+
+    for j in 0..rings {
+        builder.strip(rows[j], rows[j + 1], segments);
+    }
+
+    builder.build()
+}
+
+/*
+    A sphere made from an icosahedron, with each triangle split into four
+    again and again, for the given number of subdivisions. Unlike the uv
+    sphere, its triangles all come out about the same size. The uvs are
+    the uv sphere's.
+*/
+pub fn create_icosphere(radius: f32, subdivisions: usize) -> Mesh {
+    let t = (1.0 + f32::sqrt(5.0)) * 0.5;
+
+    let mut positions: Vec<Vec3f> = [
+        (-1.0, t, 0.0), (1.0, t, 0.0), (-1.0, -t, 0.0), (1.0, -t, 0.0),
+        (0.0, -1.0, t), (0.0, 1.0, t), (0.0, -1.0, -t), (0.0, 1.0, -t),
+        (t, 0.0, -1.0), (t, 0.0, 1.0), (-t, 0.0, -1.0), (-t, 0.0, 1.0),
+    ].iter().map(|p| Vec3f::new(p.0, p.1, p.2).normalize()).collect();
+
+    let mut faces: Vec<[usize; 3]> = vec![
+        [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+        [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+        [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+        [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+    ];
+
+    for _ in 0..subdivisions {
+        // Edges are shared, so each of their midpoints only gets made once
+        let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut midpoint = |a: usize, b: usize| -> usize {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                positions.push(((positions[a] + positions[b]) * 0.5).normalize());
+                positions.len() - 1
+            })
+        };
+
+        faces = faces.iter().flat_map(|f| {
+            let ab = midpoint(f[0], f[1]);
+            let bc = midpoint(f[1], f[2]);
+            let ca = midpoint(f[2], f[0]);
+            vec![[f[0], ab, ca], [f[1], bc, ab], [f[2], ca, bc], [ab, bc, ca]]
+        }).collect();
+    }
+
+    // Face outwards, whichever way round the table above has them
+    for f in faces.iter_mut() {
+        let (a, b, c) = (positions[f[0]], positions[f[1]], positions[f[2]]);
+        if Vec3f::dot(&Vec3f::cross(&(b - a), &(c - a)), &(a + b + c)) < 0.0 {
+            f.swap(1, 2);
+        }
+    }
+
+    // The uvs have to be worked out per corner, to deal with the seam and the poles
+    let mut uvs: Vec<Vec2f> = Vec::with_capacity(faces.len() * 3);
+    for f in faces.iter() {
+        let p = |k: usize| positions[f[k]];
+        let at_pole = |k: usize| f32::abs(p(k).x) < 1e-6 && f32::abs(p(k).z) < 1e-6;
+
+        let mut u: Vec<f32> = (0..3).map(|k| {
+            f32::atan2(p(k).x, -p(k).z) / (std::f32::consts::PI * 2.0) + 0.5
+        }).collect();
+
+        // Corners on either side of the seam go on the far side of it
+        let around: Vec<usize> = (0..3).filter(|k| !at_pole(*k)).collect();
+        let spread = around.iter().map(|k| u[*k]).fold(0.0, f32::max) - around.iter().map(|k| u[*k]).fold(1.0, f32::min);
+        if spread > 0.5 {
+            for k in around.iter() {
+                if u[*k] < 0.5 {
+                    u[*k] += 1.0;
+                }
+            }
+        }
+
+        // Any u does at a pole, so pick the one in the middle of the others
+        for k in (0..3).filter(|k| at_pole(*k)) {
+            u[k] = around.iter().map(|k| u[*k]).sum::<f32>() / around.len() as f32;
+        }
+
+        for (k, u) in u.iter().enumerate() {
+            let v = f32::asin(p(k).y.clamp(-1.0, 1.0)) / std::f32::consts::PI + 0.5;
+            uvs.push(Vec2f::new(*u, v));
+        }
+    }
+
+    let verts = positions.iter().map(|p| Vec4f::new(p.x * radius, p.y * radius, p.z * radius, 1.0)).collect();
+    let tris = faces.iter().flat_map(|f| f.to_vec()).collect();
+
+    let mut mesh = Mesh::from_corner_uvs(verts, tris, uvs).unwrap();
+    mesh.normals = Some(mesh.verts.iter().map(|p| Vec3f::from(p).normalize()).collect());
+    mesh
+}
+
+// Capped at both ends, with v running up the side
+pub fn create_cylinder(radius: f32, height: f32, segments: usize) -> Mesh {
+    assert!(segments >= 3);
+
+    let mut builder = MeshBuilder::new();
+    let h = height * 0.5;
+
+    let bottom = builder.ring(segments, 0.0, false, |out| (out * radius + Vec3f::new(0.0, -h, 0.0), out));
+    let top = builder.ring(segments, 1.0, false, |out| (out * radius + Vec3f::new(0.0, h, 0.0), out));
+    builder.strip(bottom, top, segments);
+
+    builder.cap(-h, radius, segments, false);
+    builder.cap(h, radius, segments, true);
+
+    builder.build()
+}
+
+// Its base capped, its tip pointing up
+pub fn create_cone(radius: f32, height: f32, segments: usize) -> Mesh {
+    assert!(segments >= 3);
+
+    let mut builder = MeshBuilder::new();
+    let h = height * 0.5;
+
+    // The side leans back by as much as the cone narrows
+    let normal = |out: Vec3f| (out * height + Vec3f::new(0.0, radius, 0.0)).normalize();
+
+    let base = builder.ring(segments, 0.0, false, |out| (out * radius + Vec3f::new(0.0, -h, 0.0), normal(out)));
+    let tip = builder.ring(segments, 1.0, true, |out| (Vec3f::new(0.0, h, 0.0), normal(out)));
+    builder.strip(base, tip, segments);
+
+    builder.cap(-h, radius, segments, false);
+
+    builder.build()
+}
+
+/*
+    A ring in the xz plane, major_radius out to the middle of its tube.
+    Segments go around the ring, sides around the tube, with v starting
+    and ending on the inside.
+*/
+pub fn create_torus(major_radius: f32, minor_radius: f32, segments: usize, sides: usize) -> Mesh {
+    assert!(segments >= 3 && sides >= 3);
+
+    let mut builder = MeshBuilder::new();
+    let rows: Vec<Row> = (0..=sides).map(|j| {
+        let v = j as f32 / sides as f32;
+        let angle = ((j % sides) as f32 / sides as f32 * 2.0 - 1.0) * std::f32::consts::PI;
+
+        builder.ring(segments, v, false, |out| {
+            let normal = out * f32::cos(angle) + Vec3f::new(0.0, f32::sin(angle), 0.0);
+            (out * major_radius + normal * minor_radius, normal)
+        })
+    }).collect();
+
+    for j in 0..sides {
+        builder.strip(rows[j], rows[j + 1], segments);
+    }
+
+    builder.build()
+}
+
+/*
+    A cylinder of the given height with a hemisphere on either end, so it's
+    height + 2 * radius tall in all. Rings are per hemisphere. v is spread
+    evenly along the length of the outline.
+*/
+pub fn create_capsule(radius: f32, height: f32, segments: usize, rings: usize) -> Mesh {
+    assert!(segments >= 3 && rings >= 1);
+
+    let mut builder = MeshBuilder::new();
+    let h = height * 0.5;
+    let quarter = std::f32::consts::FRAC_PI_2 * radius;
+    let length = quarter * 2.0 + height;
+
+    // Up each hemisphere from its pole, or down towards it, to its equator
+    let mut rows: Vec<Row> = Vec::with_capacity(rings * 2 + 2);
+    for (center, from_pole) in [(-h, true), (h, false)].iter() {
+        for j in 0..=rings {
+            let t = j as f32 / rings as f32;
+            let latitude = (if *from_pole { t - 1.0 } else { t }) * std::f32::consts::FRAC_PI_2;
+            let v = if *from_pole { quarter * t / length } else { 1.0 - quarter * (1.0 - t) / length };
+            let pole = (*from_pole && j == 0) || (!*from_pole && j == rings);
+
+            rows.push(builder.ring(segments, v, pole, |out| {
+                let normal = out * latitude_cos(latitude, pole) + Vec3f::new(0.0, f32::sin(latitude), 0.0);
+                (normal * radius + Vec3f::new(0.0, *center, 0.0), normal)
+            }));
+        }
+    }
+
+    for j in 0..rows.len() - 1 {
+        builder.strip(rows[j], rows[j + 1], segments);
+    }
+
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_texture_keeps_alpha() {
+        let pixels: Vec<u8> = vec![
+            255, 0, 0, 255,    0, 255, 0, 128,
+            0, 0, 255, 0,      255, 255, 255, 64,
+        ];
+        let path = std::env::temp_dir().join("ramjet_rasterizer_alpha_test.png");
+        image::save_buffer(&path, &pixels, 2, 2, image::RGBA(8)).unwrap();
+
+        let tex = load_texture(path.to_string_lossy().into_owned()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(tex.get_pixel(0, 0), Color::rgba(255, 0, 0, 255));
+        assert_eq!(tex.get_pixel(1, 0), Color::rgba(0, 255, 0, 128));
+        assert_eq!(tex.get_pixel(0, 1), Color::rgba(0, 0, 255, 0));
+        assert_eq!(tex.get_pixel(1, 1), Color::rgba(255, 255, 255, 64));
+
+        // Averaged down the mip chain along with the color
+        assert_eq!(tex.mips[0].pixels[0].a, 112);
+    }
+
+    const QUAD_OBJ: &str = "
+        # A unit quad
+        mtllib quad.mtl
+        v 0 0 0
+        v 1 0 0
+        v 1 1 0
+        v 0 1 0
+        vt 0 0
+        vt 1 0
+        vt 1 1
+        vt 0 1
+        vn 0 0 1
+        usemtl red
+        f 1/1/1 2/2/1 3/3/1 4/4/1
+    ";
+
+    #[test]
+    fn test_parse_obj_polygon() {
+        let mesh = parse_obj(QUAD_OBJ).unwrap();
+
+        assert_eq!(mesh.verts.len(), 4);
+        assert_eq!(mesh.tris, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.normals, Some(vec![Vec3f::new(0.0, 0.0, -1.0); 4]));
+        assert_eq!(mesh.uvs.len(), 4);
+        assert_eq!(mesh.uvs[2], Vec2f::new(1.0, 1.0));
+        assert_eq!(mesh.material_libs, vec![String::from("quad.mtl")]);
+        assert_eq!(mesh.submeshes, vec![SubMesh { material: String::from("red"), first_tri: 0, num_tris: 2 }]);
+    }
+
+    #[test]
+    fn test_parse_obj_negative_indices() {
+        let mesh = parse_obj("
+            v 0 0 1
+            v 0 1 1
+            v 1 1 1
+            vt 0 0
+            f -3/-1 -2/-1 -1/-1
+            v 2 2 2
+            vt 1 1
+            f -4/-2 -2/-1 -1/-1
+        ").unwrap();
+
+        // z is mirrored into our left-handed space
+        assert_eq!(mesh.verts[0], Vec4f::new(0.0, 0.0, -1.0, 1.0));
+        assert_eq!(mesh.uvs[0], Vec2f::new(0.0, 0.0));
+
+        // The third position shows up with both texture coordinates, so it's two vertices
+        assert_eq!(mesh.tris, vec![0, 1, 2, 0, 3, 4]);
+        assert_eq!(mesh.verts[3], mesh.verts[2]);
+        assert_eq!(mesh.uvs[2], Vec2f::new(0.0, 0.0));
+        assert_eq!(mesh.uvs[3], Vec2f::new(1.0, 1.0));
+        assert_eq!(mesh.uvs[4], Vec2f::new(1.0, 1.0));
+    }
+
+    #[test]
+    fn test_parse_obj_splits_vertices_by_normal() {
+        // Two faces sharing an edge, but with different normals
+        let mesh = parse_obj("
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            v 0 0 1
+            vn 0 0 1
+            vn 1 0 0
+            f 1//1 2//1 3//1
+            f 1//2 3//2 4//2
+        ").unwrap();
+
+        assert_eq!(mesh.verts.len(), 6);
+        assert_eq!(mesh.tris, vec![0, 1, 2, 3, 4, 5]);
+
+        let mesh = parse_obj("
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            vn 0 0 1
+            f 1//1 2//1 3//1
+            f 1//1 3//1 2//1
+        ").unwrap();
+
+        assert_eq!(mesh.verts.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_obj_errors() {
+        assert!(parse_obj("v 1 2").unwrap_err().contains("expects 3 to 4 values"));
+        assert!(parse_obj("v 1 2 x").unwrap_err().contains("invalid number 'x'"));
+        assert!(parse_obj("v 0 0 0\nv 1 0 0\nf 1 2").unwrap_err().contains("at least 3 vertices"));
+        assert!(parse_obj("v 0 0 0\nf 1 1 2").unwrap_err().contains("vertex index 2 out of range"));
+        assert!(parse_obj("v 0 0 0\nf 1/1 1/1 1/1").unwrap_err().contains("texture coordinate index 1"));
+        assert!(parse_obj("v 0 0 0\nf 1/a 1 1").unwrap_err().contains("invalid index 'a'"));
+        assert!(parse_obj("v 0 0 0\nf 0 1 1").unwrap_err().starts_with("2:"));
+        assert!(parse_obj("v 0 0 0\nvn 0 0 1\nf 1//1 1 1").unwrap_err().contains("mixing faces"));
+    }
+
+    #[test]
+    fn test_load_obj_missing_file() {
+        let err = load_obj(String::from("resources/does_not_exist.obj")).unwrap_err();
+        assert!(err.contains("does_not_exist.obj"));
+    }
+
+    // Every triangle faces the way its vertex normals do, which are unit length
+    fn assert_faces_out(mesh: &Mesh) {
+        let normals = mesh.normals.as_ref().unwrap();
+        assert!(mesh.validate().is_ok());
+
+        for tri in mesh.tris.chunks(3) {
+            let p = |k: usize| Vec3f::from(&mesh.verts[tri[k]]);
+            let face_normal = Vec3f::cross(&(p(1) - p(0)), &(p(2) - p(0)));
+            assert!(face_normal.length() > 0.0);
+
+            for v in tri.iter() {
+                assert!(f32::abs(normals[*v].length() - 1.0) < 1e-5);
+                assert!(Vec3f::dot(&face_normal, &normals[*v]) > 0.0);
+            }
+        }
+    }
+
+    /*
+        Textures aren't mirrored anywhere: the uvs go round the same way as
+        on create_plane. Where the uvs of a triangle don't span anything,
+        like at a pole, there's nothing to go wrong.
+    */
+    fn assert_uvs_unmirrored(mesh: &Mesh) {
+        for tri in mesh.tris.chunks(3) {
+            let uv = |k: usize| mesh.uvs[tri[k]];
+            let (duv1, duv2) = (uv(1) - uv(0), uv(2) - uv(0));
+            assert!(duv1.x * duv2.y - duv2.x * duv1.y <= 0.0);
+        }
+    }
+
+    /*
+        Consistent winding on a closed surface means that each edge gets
+        walked once in either direction, by the two triangles it lies
+        between. Edges are keyed by position, so seams don't count.
+    */
+    fn assert_closed(mesh: &Mesh) {
+        let key = |v: usize| {
+            let p = &mesh.verts[v];
+            (p.x.to_bits(), p.y.to_bits(), p.z.to_bits())
+        };
+
+        let mut edges: HashMap<_, usize> = HashMap::new();
+        for tri in mesh.tris.chunks(3) {
+            for k in 0..3 {
+                *edges.entry((key(tri[k]), key(tri[(k + 1) % 3]))).or_insert(0) += 1;
+            }
+        }
+
+        for (edge, count) in edges.iter() {
+            assert_eq!(*count, 1);
+            assert_eq!(edges.get(&(edge.1, edge.0)), Some(&1));
+        }
+    }
+
+    fn assert_in_unit_square(mesh: &Mesh) {
+        assert!(mesh.uvs.iter().all(|uv| uv.x >= 0.0 && uv.x <= 1.0 && uv.y >= 0.0 && uv.y <= 1.0));
+    }
+
+    #[test]
+    fn test_create_cube() {
+        let mesh = create_cube();
+        assert!(mesh.validate().is_ok());
+        assert_eq!(mesh.tris.len(), 12 * 3);
+        assert_closed(&mesh);
+
+        // Without normals, faces meeting at a corner can share a vertex if their uvs agree there
+        assert_eq!(mesh.verts.len(), 21);
+    }
+
+    #[test]
+    fn test_create_subdivided_plane() {
+        let mesh = create_subdivided_plane(2.0, 4);
+        assert_eq!(mesh.verts.len(), 25);
+        assert_eq!(mesh.tris.len(), 32 * 3);
+        assert_faces_out(&mesh);
+        assert_uvs_unmirrored(&mesh);
+
+        // Same corners, and texturing, as the plain one
+        assert_eq!(mesh.verts[24], Vec4f::new(2.0, 0.0, 2.0, 1.0));
+        assert_eq!(mesh.uvs[24], Vec2f::new(4.0, 4.0));
+        assert_eq!(mesh.uvs[4], Vec2f::new(4.0, 0.0));
+        assert_uvs_unmirrored(&create_plane(1.0));
+    }
+
+    #[test]
+    fn test_create_uv_sphere() {
+        let mesh = create_uv_sphere(2.0, 16, 8);
+
+        // Rings of 17 with the seam doubled up, 16 at each pole
+        assert_eq!(mesh.verts.len(), 7 * 17 + 2 * 16);
+        assert_eq!(mesh.tris.len(), 16 * 7 * 2 * 3);
+        assert!(mesh.verts.iter().all(|p| f32::abs(Vec3f::from(p).length() - 2.0) < 1e-5));
+
+        assert_faces_out(&mesh);
+        assert_uvs_unmirrored(&mesh);
+        assert_closed(&mesh);
+        assert_in_unit_square(&mesh);
+    }
+
+    #[test]
+    fn test_create_icosphere() {
+        let mesh = create_icosphere(1.5, 2);
+        assert_eq!(mesh.tris.len(), 20 * 16 * 3);
+        assert!(mesh.verts.iter().all(|p| f32::abs(Vec3f::from(p).length() - 1.5) < 1e-5));
+
+        // 162 positions, some of them split along the seam and at the poles
+        let mut positions: Vec<(u32, u32, u32)> = mesh.verts.iter().map(|p| (p.x.to_bits(), p.y.to_bits(), p.z.to_bits())).collect();
+        positions.sort();
+        positions.dedup();
+        assert_eq!(positions.len(), 162);
+
+        assert_faces_out(&mesh);
+        assert_uvs_unmirrored(&mesh);
+        assert_closed(&mesh);
+
+        // Seam triangles reach just past u = 1, rather than across the whole texture
+        assert!(mesh.uvs.iter().all(|uv| uv.x >= 0.0 && uv.x < 1.25 && uv.y >= 0.0 && uv.y <= 1.0));
+        for tri in mesh.tris.chunks(3) {
+            let u: Vec<f32> = tri.iter().map(|v| mesh.uvs[*v].x).collect();
+            assert!(u.iter().fold(0.0, |a: f32, b| a.max(*b)) - u.iter().fold(2.0, |a: f32, b| a.min(*b)) < 0.5);
+        }
+    }
+
+    #[test]
+    fn test_create_cylinder_and_cone() {
+        let cylinder = create_cylinder(1.0, 3.0, 12);
+
+        // Two rings of 13 for the side, a center and 12 around for each cap
+        assert_eq!(cylinder.verts.len(), 2 * 13 + 2 * 13);
+        assert_eq!(cylinder.tris.len(), 4 * 12 * 3);
+        assert_faces_out(&cylinder);
+        assert_uvs_unmirrored(&cylinder);
+        assert_closed(&cylinder);
+        assert_in_unit_square(&cylinder);
+
+        // A ring of 13, 12 tips, and the base
+        let cone = create_cone(1.0, 3.0, 12);
+        assert_eq!(cone.verts.len(), 13 + 12 + 13);
+        assert_eq!(cone.tris.len(), 2 * 12 * 3);
+        assert_faces_out(&cone);
+        assert_uvs_unmirrored(&cone);
+        assert_closed(&cone);
+        assert_in_unit_square(&cone);
+
+        // Tips all in the same spot, leaning outwards
+        let normals = cone.normals.as_ref().unwrap();
+        for (p, n) in cone.verts[13..25].iter().zip(normals[13..25].iter()) {
+            assert_eq!(*p, Vec4f::new(0.0, 1.5, 0.0, 1.0));
+            assert!(n.y > 0.0 && n.y < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_create_torus() {
+        let mesh = create_torus(2.0, 0.5, 24, 12);
+        assert_eq!(mesh.verts.len(), 25 * 13);
+        assert_eq!(mesh.tris.len(), 24 * 12 * 2 * 3);
+        assert_faces_out(&mesh);
+        assert_uvs_unmirrored(&mesh);
+        assert_closed(&mesh);
+        assert_in_unit_square(&mesh);
+
+        // The tube runs from 1.5 to 2.5 out from the middle
+        for p in mesh.verts.iter() {
+            let out = f32::sqrt(p.x * p.x + p.z * p.z);
+            assert!(out > 1.5 - 1e-5 && out < 2.5 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_create_capsule() {
+        let mesh = create_capsule(0.5, 2.0, 16, 4);
+
+        // 4 rings per hemisphere plus the equators, with the poles' 16 each
+        assert_eq!(mesh.verts.len(), 8 * 17 + 2 * 16);
+        assert_eq!(mesh.tris.len(), 16 * 4 * 4 * 3);
+        assert_faces_out(&mesh);
+        assert_uvs_unmirrored(&mesh);
+        assert_closed(&mesh);
+        assert_in_unit_square(&mesh);
+
+        assert!(mesh.verts.iter().all(|p| p.y >= -1.5 && p.y <= 1.5));
+        assert!(mesh.verts.iter().any(|p| p.y == 1.5));
+
+        // v runs from 0 at the bottom pole to 1 at the top one, rising row by row
+        let mut rows: Vec<(f32, f32)> = mesh.verts.iter().zip(mesh.uvs.iter()).map(|(p, uv)| (p.y, uv.y)).collect();
+        rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!(rows.iter().filter(|(y, _)| *y == -1.5).all(|(_, v)| *v == 0.0));
+        assert!(rows.iter().filter(|(y, _)| *y == 1.5).all(|(_, v)| *v == 1.0));
+        for pair in rows.windows(2) {
+            let ((y0, v0), (y1, v1)) = (pair[0], pair[1]);
+            assert!(if y0 == y1 { v0 == v1 } else { v0 < v1 });
+        }
+    }
+}
@@ -0,0 +1,162 @@
+/*
+    The demo scene: a couple of spinning textured cubes, casting shadows
+    on each other and on the ground below.
+
+    Shared by the SDL render loop and the headless renderer, so that both
+    of them draw exactly the same frames.
+*/
+
+use crate::camera::*;
+use crate::linalg::*;
+use crate::draw::*;
+use crate::light::*;
+use crate::resources::*;
+use crate::shader::*;
+use crate::shadow::*;
+use crate::texture::*;
+use crate::tiles::*;
+
+pub struct Scene {
+    pub mesh: Mesh,
+    pub ground: Mesh,
+    pub tex_checker: Texture,
+    pub tex_sprite: Texture,
+    pub tex_ground: Texture,
+    pub tex_bumps: Texture, // Normal map
+    pub camera: Camera,
+    pub lights: Vec<Light>,     // The first one casts shadows
+    pub ambient: Vec3f,
+    pub shadow_map: ShadowMap,
+}
+
+impl Scene {
+    pub fn new(width: usize, height: usize) -> Result<Scene, String> {
+        // Load our cube mesh, and something for the cubes to cast shadows on.
+        // Both get tangent frames, for normal mapping.
+        let mut mesh = create_cube();
+        let mut ground = create_plane(6.0);
+        for m in [&mut mesh, &mut ground].iter_mut() {
+            m.compute_normals(30.0);
+            m.compute_tangents();
+        }
+
+        // Camera, looking at the cubes from a little way off
+        let near: f32 = 0.1;
+        let far: f32 = 1000.0;
+        let fov: f32 = 80.0;
+        let aspect: f32 =  height as f32 / width as f32;
+        let mut camera = Camera::perspective(fov, near, far, aspect);
+        camera.set_position(&Vec3f::new(0.0, 0.0, -8.0));
+        camera.look_at(&Vec3f::new(0.0, 0.0, 0.0), &Vec3f::new(0.0, 1.0, 0.0));
+
+        // Load our textures, trilinear filtered to keep distant cubes from shimmering
+        let mut tex_checker = load_texture(String::from("resources/checker.png"))?;
+        let mut tex_sprite = load_texture(String::from("resources/test.png"))?;
+        tex_checker.sampler.filter = Filter::Trilinear;
+        tex_sprite.sampler.filter = Filter::Trilinear;
+
+        let mut tex_ground = load_texture(String::from("resources/checker.png"))?;
+        tex_ground.sampler = Sampler::with_wrap(Filter::Trilinear, Wrap::Repeat);
+
+        let mut tex_bumps = load_texture(String::from("resources/bumps_normal.png"))?;
+        tex_bumps.sampler = Sampler::with_wrap(Filter::Trilinear, Wrap::Repeat);
+
+        // Sun, shining down from behind the camera. The shadow map covers the ground and everything above it.
+        let sun_dir = Vec3f::new(-0.4, -1.0, 0.5).normalize();
+        let mut shadow_map = ShadowMap::new(512, &sun_dir, &Vec3f::new(0.0, -1.0, 0.0), 9.0);
+        shadow_map.bias = 0.005;
+
+        // Along with a warm glow down on the ground, and a cool spot lighting the cubes from the side
+        let lights = vec![
+            Light::Directional {
+                direction: sun_dir,
+                color: Vec3f::new(0.85, 0.85, 0.8),
+            },
+            Light::Point {
+                position: Vec3f::new(2.5, -2.0, -1.5),
+                color: Vec3f::new(2.0, 0.9, 0.3),
+                range: 6.0,
+            },
+            Light::Spot {
+                position: Vec3f::new(-4.5, 2.0, -2.5),
+                direction: Vec3f::new(4.5, -2.0, 2.5),
+                color: Vec3f::new(4.0, 7.0, 18.0), // Falling off with distance squared, from 5 units away
+                range: 12.0,
+                inner_angle: 0.25,
+                outer_angle: 0.45,
+            },
+        ];
+
+        Ok(Scene {
+            mesh: mesh,
+            ground: ground,
+            tex_checker: tex_checker,
+            tex_sprite: tex_sprite,
+            tex_ground: tex_ground,
+            tex_bumps: tex_bumps,
+            camera: camera,
+            lights: lights,
+            ambient: Vec3f::new(0.08, 0.08, 0.12),
+            shadow_map: shadow_map,
+        })
+    }
+
+    pub fn render(&mut self, screen: &mut Screen, time: f32) {
+        // Clear our buffer
+        clear_color(screen);
+        clear_depth(screen);
+
+        // Let's draw some cubes
+
+        // rotate and translate it in world space
+        let obj1_mat = 
+            Mat4x4f::translation(0.0, f32::sin(time * 1.0) * 1.0, 0.0) *
+            Mat4x4f::rotation_y(f32::sin(time * 3.0) * 1.0) *
+            Mat4x4f::rotation_x(f32::sin(time * 1.333) * 1.0);
+
+        let obj2_mat = 
+            Mat4x4f::translation(f32::sin(time * 1.3221) * 2.0, 0.0, 0.0) *
+            Mat4x4f::rotation_y(f32::sin(time * 2.0) * 1.0) *
+            Mat4x4f::rotation_x(f32::sin(time * 1.7672) * 1.0);
+
+        let obj3_mat = 
+            Mat4x4f::scale_uniform(0.5) *
+            Mat4x4f::translation(f32::cos(time * 0.5) * 3.0, f32::sin(time * 1.3221) * 3.0, f32::sin(time * 1.3221) * 3.0) *
+            Mat4x4f::rotation_y(f32::cos(time * 3.1) * 1.0) *
+            Mat4x4f::rotation_x(f32::sin(time * -1.0672) * 1.0);
+        
+        let ground_mat = Mat4x4f::translation(0.0, -3.0, 0.0);
+
+        // let obj1_mat = Mat4x4f::identity();
+
+        // Shadow pass, from the light. The ground has nothing below it to cast shadows on.
+        self.shadow_map.clear();
+        self.shadow_map.draw_mesh(&self.mesh, &obj1_mat);
+        self.shadow_map.draw_mesh(&self.mesh, &obj2_mat);
+        self.shadow_map.draw_mesh(&self.mesh, &obj3_mat);
+
+        let state = RenderState::new();
+        let vs = StandardVertexShader;
+        let lit = |tex| {
+            let mut shader = LitShader::new(tex, &self.lights, &self.camera.position());
+            shader.ambient = self.ambient;
+            shader.shadow_map = Some(&self.shadow_map);
+            shader
+        };
+        let sprite_shader = lit(&self.tex_sprite);
+        let mut checker_shader = lit(&self.tex_checker);
+        let mut ground_shader = lit(&self.tex_ground);
+        checker_shader.normal_map = Some(&self.tex_bumps);
+        ground_shader.normal_map = Some(&self.tex_bumps);
+
+        draw_mesh_tiled(&self.ground, &ground_mat, &self.camera, &state, &vs, &ground_shader, screen);
+        draw_mesh_tiled(&self.mesh, &obj1_mat, &self.camera, &state, &vs, &sprite_shader, screen);
+        draw_mesh_tiled(&self.mesh, &obj2_mat, &self.camera, &state, &vs, &checker_shader, screen);
+        draw_mesh_tiled(&self.mesh, &obj3_mat, &self.camera, &state, &vs, &checker_shader, screen);
+
+        // Blend in anything translucent, if the screen is set up for that,
+        // then average down the samples, if it's multisampled
+        resolve_transparency(screen);
+        resolve_samples(screen);
+    }
+}
@@ -0,0 +1,358 @@
+/*
+    Programmable shading
+
+    A vertex shader takes the attributes of a mesh vertex and produces its
+    clip-space position, plus a set of varyings: whatever per-vertex values
+    it wants to hand down to the fragment shader. The rasterizer interpolates
+    those varyings across the triangle, perspective-correct, and the fragment
+    shader turns them into a color.
+
+    Varyings can be any type that knows how to add and scale itself, which
+    is all that clipping and interpolation need.
+
+    Shaders hold their own uniforms (textures, lights, etc.) as fields. The
+    matrices for the current draw call get passed in through Transforms.
+*/
+
+#![allow(dead_code)]
+
+use crate::draw::Color;
+use crate::light::*;
+use crate::linalg::*;
+use crate::shadow::*;
+use crate::texture::*;
+
+pub trait Varyings: Copy {
+    fn add(&self, other: &Self) -> Self;
+    fn scale(&self, s: f32) -> Self;
+
+    fn lerp(a: &Self, b: &Self, t: f32) -> Self {
+        a.scale(1.0 - t).add(&b.scale(t))
+    }
+
+    // Weighted sum of 3 values, e.g. using barycentric coordinates
+    fn blend(a: &Self, b: &Self, c: &Self, w_a: f32, w_b: f32, w_c: f32) -> Self {
+        a.scale(w_a).add(&b.scale(w_b)).add(&c.scale(w_c))
+    }
+}
+
+impl Varyings for () {
+    fn add(&self, _other: &Self) -> Self {}
+    fn scale(&self, _s: f32) -> Self {}
+}
+
+impl Varyings for f32 {
+    fn add(&self, other: &Self) -> Self { self + other }
+    fn scale(&self, s: f32) -> Self { self * s }
+}
+
+impl Varyings for Vec2f {
+    fn add(&self, other: &Self) -> Self { *self + *other }
+    fn scale(&self, s: f32) -> Self { *self * s }
+}
+
+impl Varyings for Vec3f {
+    fn add(&self, other: &Self) -> Self { *self + *other }
+    fn scale(&self, s: f32) -> Self { *self * s }
+}
+
+impl Varyings for Vec4f {
+    fn add(&self, other: &Self) -> Self { *self + *other }
+    fn scale(&self, s: f32) -> Self { *self * s }
+}
+
+// The attributes of a single mesh vertex, as seen by a vertex shader
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct VertexInput {
+    pub position: Vec4f,
+    pub uv: Vec2f,
+    pub normal: Vec3f, // The face normal, if the mesh doesn't have vertex normals
+    pub color: Color,  // White, if the mesh doesn't have vertex colors
+    pub tangent: Vec4f, // Zero, if the mesh doesn't have tangents
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Transforms {
+    pub model: Mat4x4f,
+    pub view: Mat4x4f,
+    pub projection: Mat4x4f,
+    pub model_view_projection: Mat4x4f,
+    pub normal: Mat3x3f, // Takes object space normals to world space
+    pub tangent: Mat3x3f, // Likewise for tangents, which is just the model transform without translation
+    pub mirrored: bool,   // Whether the model transform flips handedness
+}
+
+impl Transforms {
+    // None if the model transform is singular, see Mat4x4f::normal_matrix
+    pub fn new(model: &Mat4x4f, view: &Mat4x4f, projection: &Mat4x4f) -> Option<Transforms> {
+        let normal = model.normal_matrix()?;
+        let tangent = Mat3x3f::from_mat4x4(model);
+
+        Some(Transforms {
+            model: *model,
+            view: *view,
+            projection: *projection,
+            model_view_projection: *projection * *view * *model,
+            normal: normal,
+            tangent: tangent,
+            mirrored: tangent.determinant() < 0.0,
+        })
+    }
+
+    // Uses the normal matrix, so that normals stay correct under non-uniform scale
+    pub fn model_normal(&self, n: &Vec3f) -> Vec3f {
+        (self.normal * *n).normalize()
+    }
+
+    /*
+        Tangents lie along the surface, so they transform like positions do,
+        rather than like normals. A mirroring transform flips the handedness.
+        Zero tangents stay zero.
+    */
+    pub fn model_tangent(&self, t: &Vec4f) -> Vec4f {
+        let tangent = self.tangent * Vec3f::from(t);
+
+        if tangent.length() == 0.0 {
+            return Vec4f::new(0.0, 0.0, 0.0, 0.0);
+        }
+
+        let tangent = tangent.normalize();
+        let w = if self.mirrored { -t.w } else { t.w };
+        Vec4f::new(tangent.x, tangent.y, tangent.z, w)
+    }
+}
+
+/*
+    What a fragment shader gets to work with. Along with the interpolated
+    varyings, we pass how much they change when stepping one pixel right
+    (ddx) or down (ddy), which is what texture LOD selection needs.
+*/
+pub struct Fragment<V: Varyings> {
+    pub x: usize,
+    pub y: usize,
+    pub depth: f32,
+    pub varyings: V,
+    pub ddx: V,
+    pub ddy: V,
+}
+
+pub trait VertexShader {
+    type Output: Varyings;
+
+    // Returns the clip-space position, and the varyings for this vertex
+    fn vertex(&self, input: &VertexInput, transforms: &Transforms) -> (Vec4f, Self::Output);
+}
+
+pub trait FragmentShader<V: Varyings> {
+    // Returns the fragment's color, or None to discard it
+    fn fragment(&self, frag: &Fragment<V>) -> Option<Color>;
+}
+
+/*--------------------
+    Built-in shaders
+--------------------*/
+
+// Covers what the built-in fragment shaders need
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct StandardVaryings {
+    pub uv: Vec2f,
+    pub normal: Vec3f,    // World space
+    pub color: Vec4f,     // Vertex color, RGBA in [0,1]
+    pub world_pos: Vec3f,
+    pub tangent: Vec4f,   // World space, with handedness in w
+}
+
+impl Varyings for StandardVaryings {
+    fn add(&self, other: &Self) -> Self {
+        StandardVaryings {
+            uv: self.uv + other.uv,
+            normal: self.normal + other.normal,
+            color: self.color + other.color,
+            world_pos: self.world_pos + other.world_pos,
+            tangent: self.tangent + other.tangent,
+        }
+    }
+
+    fn scale(&self, s: f32) -> Self {
+        StandardVaryings {
+            uv: self.uv * s,
+            normal: self.normal * s,
+            color: self.color * s,
+            world_pos: self.world_pos * s,
+            tangent: self.tangent * s,
+        }
+    }
+}
+
+pub struct StandardVertexShader;
+
+impl VertexShader for StandardVertexShader {
+    type Output = StandardVaryings;
+
+    fn vertex(&self, input: &VertexInput, transforms: &Transforms) -> (Vec4f, StandardVaryings) {
+        let world_pos = transforms.model * input.position;
+
+        let varyings = StandardVaryings {
+            uv: input.uv,
+            normal: transforms.model_normal(&input.normal),
+            color: input.color.to_vec4(),
+            world_pos: Vec3f::from(&world_pos),
+            tangent: transforms.model_tangent(&input.tangent),
+        };
+
+        (transforms.model_view_projection * input.position, varyings)
+    }
+}
+
+// Position only, for depth-only passes like shadow maps. Pair with RenderState::depth_only.
+pub struct DepthOnlyShader;
+
+impl VertexShader for DepthOnlyShader {
+    type Output = ();
+
+    fn vertex(&self, input: &VertexInput, transforms: &Transforms) -> (Vec4f, ()) {
+        (transforms.model_view_projection * input.position, ())
+    }
+}
+
+impl FragmentShader<()> for DepthOnlyShader {
+    fn fragment(&self, _frag: &Fragment<()>) -> Option<Color> {
+        Some(Color::black())
+    }
+}
+
+// Sample a texture at the fragment's UV, with LOD from its derivatives
+fn sample_albedo(tex: &Texture, frag: &Fragment<StandardVaryings>) -> Color {
+    let lod = tex.lod(&frag.ddx.uv, &frag.ddy.uv);
+    tex.sample(&frag.varyings.uv, lod)
+}
+
+/*
+    The fragment's world space normal, perturbed by a tangent-space normal
+    map if there is one. Normal maps store x along the tangent (+u), y along
+    the bitangent (+v, so up in the image) and z along the normal, each
+    mapped from [-1,1] to [0,255], which makes a flat surface (128,128,255).
+
+    Without tangents on the mesh there's no frame to put the map's normals
+    in, so those get the interpolated normal as is.
+*/
+pub fn shading_normal(normal_map: Option<&Texture>, frag: &Fragment<StandardVaryings>) -> Vec3f {
+    let normal = frag.varyings.normal.normalize();
+
+    let map = match normal_map {
+        Some(map) => map,
+        None => return normal,
+    };
+
+    let t = frag.varyings.tangent;
+    let tangent = Vec3f::from(&t);
+    if tangent.length() == 0.0 {
+        return normal;
+    }
+
+    // Interpolation leaves the frame a little skewed, so straighten it out again
+    let tangent = (tangent - normal * Vec3f::dot(&tangent, &normal)).normalize();
+    let handedness = if t.w < 0.0 { -1.0 } else { 1.0 };
+    let bitangent = Vec3f::cross(&normal, &tangent) * handedness;
+
+    let m = sample_albedo(map, frag).to_vec3() * 2.0 - Vec3f::new(1.0, 1.0, 1.0);
+    (tangent * m.x + bitangent * m.y + normal * m.z).normalize()
+}
+
+// Just the texture, no lighting
+pub struct UnlitShader<'a> {
+    pub tex: &'a Texture,
+}
+
+impl<'a> FragmentShader<StandardVaryings> for UnlitShader<'a> {
+    fn fragment(&self, frag: &Fragment<StandardVaryings>) -> Option<Color> {
+        Some(sample_albedo(self.tex, frag))
+    }
+}
+
+pub struct VertexColorShader;
+
+impl FragmentShader<StandardVaryings> for VertexColorShader {
+    fn fragment(&self, frag: &Fragment<StandardVaryings>) -> Option<Color> {
+        Some(Color::from_vec4(&frag.varyings.color))
+    }
+}
+
+// Maps world-space normals from [-1,1] to colors in [0,1], for debugging
+pub struct NormalShader;
+
+impl FragmentShader<StandardVaryings> for NormalShader {
+    fn fragment(&self, frag: &Fragment<StandardVaryings>) -> Option<Color> {
+        let n = frag.varyings.normal.normalize();
+        Some(Color::from_vec3(&(n * 0.5 + Vec3f::new(0.5, 0.5, 0.5))))
+    }
+}
+
+/*
+    Textured, lit by a list of lights: Lambert diffuse, plus Blinn-Phong
+    highlights of the lights' own color, on top of a constant ambient term
+    that stands in for light bounced around the scene.
+*/
+pub struct LitShader<'a> {
+    pub tex: &'a Texture,
+    pub lights: &'a [Light],
+    pub eye: Vec3f,       // World space camera position, which highlights depend on
+    pub ambient: Vec3f,   // Light that reaches everywhere, as a color
+    pub specular: f32,    // Strength of the highlights
+    pub shininess: f32,   // Blinn-Phong exponent. Higher gives smaller, sharper highlights.
+    pub shadow_map: Option<&'a ShadowMap>, // Shadows the first light in the list
+    pub normal_map: Option<&'a Texture>,   // Tangent space, needs a mesh with tangents
+}
+
+impl<'a> LitShader<'a> {
+    pub fn new(tex: &'a Texture, lights: &'a [Light], eye: &Vec3f) -> LitShader<'a> {
+        LitShader {
+            tex: tex,
+            lights: lights,
+            eye: *eye,
+            ambient: Vec3f::new(0.1, 0.1, 0.1),
+            specular: 0.25,
+            shininess: 32.0,
+            shadow_map: None,
+            normal_map: None,
+        }
+    }
+}
+
+impl<'a> FragmentShader<StandardVaryings> for LitShader<'a> {
+    fn fragment(&self, frag: &Fragment<StandardVaryings>) -> Option<Color> {
+        let albedo = sample_albedo(self.tex, frag);
+
+        let normal = shading_normal(self.normal_map, frag);
+        let world_pos = frag.varyings.world_pos;
+        let to_eye = (self.eye - world_pos).normalize();
+
+        let mut diffuse = self.ambient;
+        let mut specular = Vec3f::new(0.0, 0.0, 0.0);
+
+        for (i, light) in self.lights.iter().enumerate() {
+            let (to_light, mut radiance) = light.illuminate(&world_pos);
+
+            // Facing away, so no need to look up shadows either
+            let n_dot_l = Vec3f::dot(&normal, &to_light);
+            if n_dot_l <= 0.0 {
+                continue;
+            }
+
+            if i == 0 {
+                if let Some(shadow_map) = self.shadow_map {
+                    radiance *= shadow_map.visibility(&world_pos);
+                }
+            }
+
+            let half = (to_light + to_eye).normalize();
+            let n_dot_h = f32::max(0.0, Vec3f::dot(&normal, &half));
+
+            diffuse = diffuse + radiance * n_dot_l;
+            specular = specular + radiance * (f32::powf(n_dot_h, self.shininess) * n_dot_l);
+        }
+
+        let color = albedo.to_vec3() * diffuse + specular * self.specular;
+        Some(Color::from_vec3(&color).with_alpha(albedo.a))
+    }
+}
@@ -0,0 +1,169 @@
+/*
+    Shadow mapping, for a directional light
+
+    First a depth-only pass renders the shadow casters as seen from the
+    light. A directional light's rays run parallel, so that view is an
+    orthographic one. What ends up in the depth buffer is how far along
+    each ray the nearest occluder lies. Then, while shading the main pass,
+    a point is in shadow if it lies further along its ray than that.
+
+    Bias moves the comparison a little towards the light, so that surfaces
+    don't shadow themselves through the limited resolution and precision
+    of the map, which shows up as stripes called shadow acne. Too much of
+    it and shadows come loose from the objects casting them.
+
+    PCF, percentage-closer filtering, does the comparison for a square of
+    neighbouring texels and averages the results, softening the stairs
+    along shadow edges. Note that it's the comparisons that get averaged,
+    averaging depths first would give meaningless results.
+*/
+
+use crate::camera::*;
+use crate::draw::*;
+use crate::linalg::*;
+use crate::shader::*;
+use crate::tiles::*;
+
+pub struct ShadowMap {
+    pub map: Screen,        // Depth only, see Screen::depth_only
+    pub camera: Camera,     // The light's point of view
+    pub view_proj: Mat4x4f, // The camera's view and projection in one, as of the last clear
+    pub bias: f32,          // In depth buffer units, which span the light's near to far plane
+    pub pcf_radius: usize,  // Texels to either side, so 1 samples 3x3. 0 gives hard shadows.
+}
+
+impl ShadowMap {
+    /*
+        A square map that covers a sphere of the given radius around center,
+        which should hold everything that casts or receives shadows.
+    */
+    pub fn new(size: usize, light_dir: &Vec3f, center: &Vec3f, radius: f32) -> ShadowMap {
+        let light_dir = light_dir.normalize();
+
+        // Any up vector will do, as long as it's not parallel to the light
+        let up = if f32::abs(light_dir.y) < 0.99 { Vec3f::new(0.0, 1.0, 0.0) } else { Vec3f::new(0.0, 0.0, 1.0) };
+
+        let mut camera = Camera::orthographic(radius * 2.0, 0.0, radius * 2.0, 1.0);
+        camera.set_position(&(*center - light_dir * radius));
+        camera.look_at(center, &up);
+
+        ShadowMap {
+            map: Screen::depth_only(size, size),
+            camera: camera,
+            view_proj: *camera.proj() * *camera.view(),
+            bias: 0.002,
+            pcf_radius: 1,
+        }
+    }
+
+    // Starts a new pass, picking up any change to the camera since the last one
+    pub fn clear(&mut self) {
+        self.view_proj = *self.camera.proj() * *self.camera.view();
+        clear_depth(&mut self.map);
+    }
+
+    // Renders a shadow caster into the map
+    pub fn draw_mesh(&mut self, mesh: &Mesh, transform: &Mat4x4f) {
+        let shader = DepthOnlyShader;
+        draw_mesh_tiled(mesh, transform, &self.camera, &RenderState::depth_only(), &shader, &shader, &mut self.map);
+    }
+
+    /*
+        How much light reaches a world-space point, from 0 in full shadow to
+        1 fully lit. Anything outside of the map counts as lit, as there is
+        nothing there to block the light.
+    */
+    pub fn visibility(&self, world_pos: &Vec3f) -> f32 {
+        let p = self.view_proj * Vec4f::new(world_pos.x, world_pos.y, world_pos.z, 1.0);
+
+        if p.z < 0.0 || p.z > 1.0 {
+            return 1.0;
+        }
+
+        // Into texels, the same way the rasterizer maps to pixels
+        let width = self.map.width as i32;
+        let height = self.map.height as i32;
+        let x = ((p.x + 0.5) * width as f32).floor() as i32;
+        let y = ((0.5 - p.y) * height as f32).floor() as i32;
+
+        let radius = self.pcf_radius as i32;
+        let mut lit = 0;
+
+        for sy in y - radius..=y + radius {
+            for sx in x - radius..=x + radius {
+                if sx < 0 || sy < 0 || sx >= width || sy >= height {
+                    lit += 1;
+                    continue;
+                }
+
+                let occluder = self.map.depth[(sy * width + sx) as usize];
+                if p.z - self.bias <= occluder {
+                    lit += 1;
+                }
+            }
+        }
+
+        let samples = (radius * 2 + 1) * (radius * 2 + 1);
+        lit as f32 / samples as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::*;
+
+    // Light shining straight down, onto a 2x2 quad held up at y = 1 above the origin
+    fn create_occluded_map(pcf_radius: usize) -> ShadowMap {
+        let mut shadow_map = ShadowMap::new(64, &Vec3f::new(0.0, -1.0, 0.0), &Vec3f::new(0.0, 0.0, 0.0), 4.0);
+        shadow_map.pcf_radius = pcf_radius;
+
+        let occluder = create_plane(1.0);
+        shadow_map.clear();
+        shadow_map.draw_mesh(&occluder, &Mat4x4f::translation(0.0, 1.0, 0.0));
+        shadow_map
+    }
+
+    #[test]
+    fn test_shadow_map_occlusion() {
+        let shadow_map = create_occluded_map(0);
+        assert!(shadow_map.map.color.is_empty());
+
+        // Below the occluder, on the ground
+        assert_eq!(shadow_map.visibility(&Vec3f::new(0.0, 0.0, 0.0)), 0.0);
+        assert_eq!(shadow_map.visibility(&Vec3f::new(0.9, -2.0, -0.9)), 0.0);
+
+        // Beside it, above it, on it, and off the map entirely
+        assert_eq!(shadow_map.visibility(&Vec3f::new(1.5, 0.0, 0.0)), 1.0);
+        assert_eq!(shadow_map.visibility(&Vec3f::new(0.0, 2.0, 0.0)), 1.0);
+        assert_eq!(shadow_map.visibility(&Vec3f::new(0.0, 1.0, 0.0)), 1.0);
+        assert_eq!(shadow_map.visibility(&Vec3f::new(10.0, 0.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn test_shadow_map_bias() {
+        // The occluder itself is level with what it left in the map, so it's
+        // the bias that decides whether it ends up in its own shadow
+        let mut shadow_map = create_occluded_map(0);
+        shadow_map.bias = -0.001;
+        assert_eq!(shadow_map.visibility(&Vec3f::new(0.0, 1.0, 0.0)), 0.0);
+
+        shadow_map.bias = 0.001;
+        assert_eq!(shadow_map.visibility(&Vec3f::new(0.0, 1.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn test_shadow_map_pcf() {
+        // Texels are 8/64 world units wide. Half a texel in from the shadow's edge...
+        let edge = Vec3f::new(1.0 - 1.0 / 16.0, 0.0, 0.0);
+
+        // ...is a hard edge without PCF
+        assert_eq!(create_occluded_map(0).visibility(&edge), 0.0);
+
+        // With it, 1 column of the 3x3 samples falls outside the shadow
+        let shadow_map = create_occluded_map(1);
+        assert_eq!(shadow_map.visibility(&edge), 3.0 / 9.0);
+        assert_eq!(shadow_map.visibility(&Vec3f::new(0.0, 0.0, 0.0)), 0.0);
+        assert_eq!(shadow_map.visibility(&Vec3f::new(1.5, 0.0, 0.0)), 1.0);
+    }
+}
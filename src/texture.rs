@@ -0,0 +1,480 @@
+/*
+    Textures, and how we read from them.
+
+    A texture knows its own dimensions and memory layout, so that the
+    rasterizer doesn't need to make any assumptions about either. It can
+    also carry a chain of mipmaps, successively halved in size, which the
+    sampler picks from based on how many texels land in a screen pixel.
+*/
+
+#![allow(dead_code)]
+
+use crate::draw::Color;
+use crate::linalg::*;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TextureLayout {
+    RowMajor,    // pixels[y * width + x]
+    ColumnMajor, // pixels[x * height + y]
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Filter {
+    Nearest,   // Nearest texel on the nearest mip level
+    Bilinear,  // Blend 4 texels on the nearest mip level
+    Trilinear, // Bilinear on the two nearest mip levels, blended by lod
+}
+
+// What to do with texel coordinates that fall outside of the texture
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Wrap {
+    Repeat, // Tile the texture
+    Clamp,  // Stretch the edge texels outwards
+    Mirror, // Tile the texture, flipping every other tile
+    Border, // Use the sampler's border color
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Sampler {
+    pub filter: Filter,
+    pub wrap_u: Wrap,
+    pub wrap_v: Wrap,
+    pub border: Color,
+}
+
+impl Sampler {
+    pub fn new(filter: Filter) -> Sampler {
+        Sampler {
+            filter: filter,
+            wrap_u: Wrap::Clamp,
+            wrap_v: Wrap::Clamp,
+            border: Color::black(),
+        }
+    }
+
+    pub fn with_wrap(filter: Filter, wrap: Wrap) -> Sampler {
+        Sampler {
+            filter: filter,
+            wrap_u: wrap,
+            wrap_v: wrap,
+            border: Color::black(),
+        }
+    }
+}
+
+// Maps a texel coordinate into [0, size), or None if it should read the border color
+fn wrap_texel(i: i32, size: usize, wrap: Wrap) -> Option<usize> {
+    let size = size as i32;
+
+    match wrap {
+        Wrap::Repeat => Some(i.rem_euclid(size) as usize),
+        Wrap::Clamp => Some(i32::min(i32::max(i, 0), size - 1) as usize),
+        Wrap::Mirror => {
+            let i = i.rem_euclid(2 * size);
+            Some(if i < size { i } else { 2 * size - 1 - i } as usize)
+        },
+        Wrap::Border => {
+            if i >= 0 && i < size { Some(i as usize) } else { None }
+        },
+    }
+}
+
+// Mip levels are always stored row-major
+pub struct MipLevel {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub layout: TextureLayout,
+    pub pixels: Vec<Color>,
+    pub mips: Vec<MipLevel>, // mips[0] is half the size of the texture itself
+    pub sampler: Sampler,
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, layout: TextureLayout, pixels: Vec<Color>) -> Texture {
+        assert!(width > 0 && height > 0, "Texture dimensions must be non-zero: {}x{}", width, height);
+        assert_eq!(pixels.len(), width * height, "Texture pixel count doesn't match its {}x{} dimensions", width, height);
+
+        Texture {
+            width: width,
+            height: height,
+            layout: layout,
+            pixels: pixels,
+            mips: Vec::new(),
+            sampler: Sampler::new(Filter::Nearest),
+        }
+    }
+
+    // Texel coordinates, with [0,0] the top-left of the image
+    pub fn get_pixel(&self, x: usize, y: usize) -> Color {
+        assert!(x < self.width);
+        assert!(y < self.height);
+
+        let offset = match self.layout {
+            TextureLayout::RowMajor => y * self.width + x,
+            TextureLayout::ColumnMajor => x * self.height + y,
+        };
+
+        self.pixels[offset]
+    }
+
+    pub fn num_levels(&self) -> usize {
+        1 + self.mips.len()
+    }
+
+    pub fn level_size(&self, level: usize) -> (usize, usize) {
+        if level == 0 {
+            (self.width, self.height)
+        } else {
+            let mip = &self.mips[level - 1];
+            (mip.width, mip.height)
+        }
+    }
+
+    fn get_level_pixel(&self, level: usize, x: usize, y: usize) -> Color {
+        if level == 0 {
+            self.get_pixel(x, y)
+        } else {
+            let mip = &self.mips[level - 1];
+            mip.pixels[y * mip.width + x]
+        }
+    }
+
+    /*
+        Builds the full mip chain down to 1x1 by box filtering each level
+        from the one before it. Odd dimensions round down, with the last
+        row or column folded into the one before it: the last texel of the
+        level below averages three texels along that axis instead of two.
+    */
+    pub fn generate_mips(&mut self) {
+        self.mips.clear();
+
+        let mut level = 0;
+        let (mut width, mut height) = (self.width, self.height);
+
+        // Source texels [start, end) that output texel i averages along an axis
+        let footprint = |i: usize, size: usize, mip_size: usize| -> (usize, usize) {
+            let end = if i + 1 == mip_size { size } else { i * 2 + 2 };
+            (usize::min(i * 2, size - 1), end)
+        };
+
+        while width > 1 || height > 1 {
+            let mip_width = usize::max(1, width / 2);
+            let mip_height = usize::max(1, height / 2);
+            let mut pixels = Vec::with_capacity(mip_width * mip_height);
+
+            for y in 0..mip_height {
+                for x in 0..mip_width {
+                    let (x0, x1) = footprint(x, width, mip_width);
+                    let (y0, y1) = footprint(y, height, mip_height);
+
+                    let mut sum = [0u32; 4];
+                    for sy in y0..y1 {
+                        for sx in x0..x1 {
+                            let c = self.get_level_pixel(level, sx, sy);
+                            sum[0] += c.r as u32;
+                            sum[1] += c.g as u32;
+                            sum[2] += c.b as u32;
+                            sum[3] += c.a as u32;
+                        }
+                    }
+
+                    let n = ((x1 - x0) * (y1 - y0)) as u32;
+                    pixels.push(Color::rgba(
+                        ((sum[0] + n / 2) / n) as u8,
+                        ((sum[1] + n / 2) / n) as u8,
+                        ((sum[2] + n / 2) / n) as u8,
+                        ((sum[3] + n / 2) / n) as u8));
+                }
+            }
+
+            self.mips.push(MipLevel {
+                width: mip_width,
+                height: mip_height,
+                pixels: pixels,
+            });
+
+            level += 1;
+            width = mip_width;
+            height = mip_height;
+        }
+    }
+
+    /*
+        Level of detail from the screen-space derivatives of UV, i.e. how far
+        UV moves when stepping one pixel right (dx) or down (dy). We take the
+        larger of the two footprints, measured in texels of the base level.
+
+        0 means one texel per pixel, 1 means two, and so on. Negative values
+        mean the texture is being magnified.
+    */
+    pub fn lod(&self, duv_dx: &Vec2f, duv_dy: &Vec2f) -> f32 {
+        let size = Vec2f::new(self.width as f32, self.height as f32);
+        let dx = *duv_dx * size;
+        let dy = *duv_dy * size;
+
+        let footprint = f32::max(
+            dx.x * dx.x + dx.y * dx.y,
+            dy.x * dy.x + dy.y * dy.y);
+
+        // log2(sqrt(f)) == 0.5 * log2(f)
+        0.5 * f32::log2(footprint)
+    }
+
+    /*
+        Sample at the given UV coordinate and level of detail, using this
+        texture's sampler. UV [0,0] is the bottom-left of the image, [1,1]
+        the top-right. Coordinates outside of that range are handled by the
+        sampler's wrap modes.
+    */
+    pub fn sample(&self, uv: &Vec2f, lod: f32) -> Color {
+        let max_level = (self.num_levels() - 1) as f32;
+        let lod = f32::min(f32::max(lod, 0.0), max_level);
+
+        match self.sampler.filter {
+            Filter::Nearest => self.sample_nearest(uv, (lod + 0.5) as usize),
+            Filter::Bilinear => self.sample_bilinear(uv, (lod + 0.5) as usize),
+            Filter::Trilinear => {
+                let level = lod as usize;
+                let a = self.sample_bilinear(uv, level);
+                if level as f32 == max_level {
+                    a
+                } else {
+                    let b = self.sample_bilinear(uv, level + 1);
+                    Color::lerp(a, b, lod - level as f32)
+                }
+            },
+        }
+    }
+
+    // Fetch a single texel, with coordinates wrapped according to the sampler
+    fn fetch(&self, level: usize, x: i32, y: i32) -> Color {
+        let (width, height) = self.level_size(level);
+
+        let x = wrap_texel(x, width, self.sampler.wrap_u);
+        let y = wrap_texel(y, height, self.sampler.wrap_v);
+
+        match (x, y) {
+            (Some(x), Some(y)) => self.get_level_pixel(level, x, y),
+            _ => self.sampler.border,
+        }
+    }
+
+    fn sample_nearest(&self, uv: &Vec2f, level: usize) -> Color {
+        let (width, height) = self.level_size(level);
+
+        let x = f32::floor(uv.x * width as f32) as i32;
+        let y = f32::floor((1.0 - uv.y) * height as f32) as i32;
+
+        self.fetch(level, x, y)
+    }
+
+    fn sample_bilinear(&self, uv: &Vec2f, level: usize) -> Color {
+        let (width, height) = self.level_size(level);
+
+        // Texel centers lie at half-integer coordinates
+        let x = uv.x * width as f32 - 0.5;
+        let y = (1.0 - uv.y) * height as f32 - 0.5;
+
+        let x0 = f32::floor(x);
+        let y0 = f32::floor(y);
+        let tx = x - x0;
+        let ty = y - y0;
+
+        let x0 = x0 as i32;
+        let y0 = y0 as i32;
+
+        let c00 = self.fetch(level, x0, y0);
+        let c10 = self.fetch(level, x0 + 1, y0);
+        let c01 = self.fetch(level, x0, y0 + 1);
+        let c11 = self.fetch(level, x0 + 1, y0 + 1);
+
+        Color::lerp(
+            Color::lerp(c00, c10, tx),
+            Color::lerp(c01, c11, tx),
+            ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    // 4x2 texture where every texel has a unique color
+    fn create_test_texture(layout: TextureLayout) -> Texture {
+        let mut pixels = Vec::new();
+        match layout {
+            TextureLayout::RowMajor => {
+                for y in 0..2 { for x in 0..4 { pixels.push(Color::new(x, y, 0)); } }
+            },
+            TextureLayout::ColumnMajor => {
+                for x in 0..4 { for y in 0..2 { pixels.push(Color::new(x, y, 0)); } }
+            },
+        }
+        Texture::new(4, 2, layout, pixels)
+    }
+
+    #[test]
+    fn test_get_pixel_layouts() {
+        let row = create_test_texture(TextureLayout::RowMajor);
+        let col = create_test_texture(TextureLayout::ColumnMajor);
+
+        for y in 0..2 {
+            for x in 0..4 {
+                assert_eq!(row.get_pixel(x, y), Color::new(x as u8, y as u8, 0));
+                assert_eq!(col.get_pixel(x, y), row.get_pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_non_square() {
+        let tex = create_test_texture(TextureLayout::RowMajor);
+
+        // Bottom-left, top-right, and the far edges which must not read out of bounds
+        assert_eq!(tex.sample(&Vec2f::new(0.0, 0.0), 0.0), Color::new(0, 1, 0));
+        assert_eq!(tex.sample(&Vec2f::new(0.9, 0.9), 0.0), Color::new(3, 0, 0));
+        assert_eq!(tex.sample(&Vec2f::new(1.0, 1.0), 0.0), Color::new(3, 0, 0));
+        assert_eq!(tex.sample(&Vec2f::new(0.3, 0.0), 0.0), Color::new(1, 1, 0));
+    }
+
+    // 4x1 texture with a gradient from dark to bright, for testing wrap modes
+    fn create_wrap_texture(wrap: Wrap) -> Texture {
+        let pixels = (0..4).map(|x| Color::new(x * 10 + 10, 0, 0)).collect();
+        let mut tex = Texture::new(4, 1, TextureLayout::RowMajor, pixels);
+        tex.sampler = Sampler::with_wrap(Filter::Nearest, wrap);
+        tex.sampler.border = Color::blue();
+        tex
+    }
+
+    fn sample_u(tex: &Texture, u: f32) -> Color {
+        tex.sample(&Vec2f::new(u, 0.5), 0.0)
+    }
+
+    #[test]
+    fn test_wrap_repeat() {
+        let tex = create_wrap_texture(Wrap::Repeat);
+        assert_eq!(sample_u(&tex, 0.0), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, 0.99), Color::new(40, 0, 0));
+        assert_eq!(sample_u(&tex, 1.0), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, 1.3), Color::new(20, 0, 0));
+        assert_eq!(sample_u(&tex, -0.01), Color::new(40, 0, 0));
+        assert_eq!(sample_u(&tex, -2.9), Color::new(10, 0, 0));
+    }
+
+    #[test]
+    fn test_wrap_clamp() {
+        let tex = create_wrap_texture(Wrap::Clamp);
+        assert_eq!(sample_u(&tex, 0.0), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, 1.0), Color::new(40, 0, 0));
+        assert_eq!(sample_u(&tex, 5.0), Color::new(40, 0, 0));
+        assert_eq!(sample_u(&tex, -0.01), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, -5.0), Color::new(10, 0, 0));
+    }
+
+    #[test]
+    fn test_wrap_mirror() {
+        let tex = create_wrap_texture(Wrap::Mirror);
+        assert_eq!(sample_u(&tex, 0.99), Color::new(40, 0, 0));
+        assert_eq!(sample_u(&tex, 1.0), Color::new(40, 0, 0));
+        assert_eq!(sample_u(&tex, 1.3), Color::new(30, 0, 0));
+        assert_eq!(sample_u(&tex, 1.99), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, 2.0), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, -0.01), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, -0.3), Color::new(20, 0, 0));
+    }
+
+    #[test]
+    fn test_wrap_border() {
+        let tex = create_wrap_texture(Wrap::Border);
+        assert_eq!(sample_u(&tex, 0.0), Color::new(10, 0, 0));
+        assert_eq!(sample_u(&tex, 0.99), Color::new(40, 0, 0));
+        assert_eq!(sample_u(&tex, 1.0), Color::blue());
+        assert_eq!(sample_u(&tex, -0.01), Color::blue());
+    }
+
+    #[test]
+    fn test_wrap_bilinear_across_edge() {
+        // Halfway between the last and first texel centers, blending across the seam
+        let mut tex = create_wrap_texture(Wrap::Repeat);
+        tex.sampler.filter = Filter::Bilinear;
+        assert_eq!(sample_u(&tex, 1.0), Color::new(25, 0, 0));
+
+        tex.sampler.wrap_u = Wrap::Clamp;
+        assert_eq!(sample_u(&tex, 1.0), Color::new(40, 0, 0));
+
+        tex.sampler.wrap_u = Wrap::Border;
+        assert_eq!(sample_u(&tex, 1.0), Color::new(20, 0, 128));
+    }
+
+    #[test]
+    fn test_generate_mips() {
+        let mut tex = Texture::new(8, 2, TextureLayout::RowMajor, vec![Color::new(100, 50, 0); 16]);
+        tex.generate_mips();
+
+        // 8x2 -> 4x1 -> 2x1 -> 1x1
+        assert_eq!(tex.num_levels(), 4);
+        assert_eq!(tex.level_size(1), (4, 1));
+        assert_eq!(tex.level_size(3), (1, 1));
+        assert_eq!(tex.mips[2].pixels[0], Color::new(100, 50, 0));
+    }
+
+    #[test]
+    fn test_generate_mips_odd_size() {
+        // 5x4, with red counting up along x and green along y
+        let mut pixels = Vec::new();
+        for y in 0..4 { for x in 0..5 { pixels.push(Color::new(x * 10, y * 10, 0)); } }
+        let mut tex = Texture::new(5, 4, TextureLayout::RowMajor, pixels);
+        tex.generate_mips();
+
+        // The last texel along x averages the last three columns, none get dropped
+        assert_eq!(tex.level_size(1), (2, 2));
+        assert_eq!(tex.mips[0].pixels, vec![
+            Color::new(5, 5, 0), Color::new(30, 5, 0),
+            Color::new(5, 25, 0), Color::new(30, 25, 0)]);
+
+        assert_eq!(tex.mips[1].pixels, vec![Color::new(18, 15, 0)]);
+    }
+
+    #[test]
+    fn test_lod_from_uv_gradient() {
+        let tex = Texture::new(256, 256, TextureLayout::RowMajor, vec![Color::black(); 256 * 256]);
+
+        // One texel per pixel
+        let lod = tex.lod(&Vec2f::new(1.0 / 256.0, 0.0), &Vec2f::new(0.0, 1.0 / 256.0));
+        assert_approx_eq!(lod, 0.0);
+
+        // Four texels per pixel along x, one along y: larger footprint wins
+        let lod = tex.lod(&Vec2f::new(4.0 / 256.0, 0.0), &Vec2f::new(0.0, 1.0 / 256.0));
+        assert_approx_eq!(lod, 2.0);
+
+        // Diagonal gradient, length sqrt(8^2 + 8^2) texels
+        let lod = tex.lod(&Vec2f::new(8.0 / 256.0, 8.0 / 256.0), &Vec2f::new(0.0, 0.0));
+        assert_approx_eq!(lod, 3.5);
+    }
+
+    #[test]
+    fn test_filter_modes() {
+        // 2x1 texture, black on the left and white on the right
+        let mut tex = Texture::new(2, 1, TextureLayout::RowMajor, vec![Color::black(), Color::white()]);
+        tex.generate_mips();
+
+        let center = Vec2f::new(0.5, 0.5);
+
+        tex.sampler.filter = Filter::Nearest;
+        assert_eq!(tex.sample(&center, 0.0), Color::white());
+
+        tex.sampler.filter = Filter::Bilinear;
+        assert_eq!(tex.sample(&center, 0.0), Color::new(128, 128, 128));
+
+        // Halfway between level 0 (black at the left texel center) and level 1 (gray)
+        tex.sampler.filter = Filter::Trilinear;
+        assert_eq!(tex.sample(&Vec2f::new(0.25, 0.5), 0.5), Color::new(64, 64, 64));
+    }
+}
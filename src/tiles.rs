@@ -0,0 +1,218 @@
+/*
+    Tiled, multi-threaded rasterization
+
+    Triangles first go through the same geometry stage as with draw_mesh.
+    We then bin them into square screen tiles by their bounding boxes, and
+    draw each tile as a task of its own, for rayon to spread over threads.
+    Every tile is a view onto its own slices of the color, depth and
+    A-buffer rows it covers, see Screen::tiles, so there's no sharing to
+    worry about. Tiles no triangle touches are skipped.
+
+    Within a tile, triangles are drawn in the order they were submitted,
+    and the rasterizer evaluates each pixel independently of where the tile
+    starts, so the output is identical to that of the single-threaded path.
+
+    Todo:
+    - Batch up a whole frame's worth of triangles, instead of syncing per draw
+*/
+
+use rayon::prelude::*;
+
+use crate::camera::*;
+use crate::draw::*;
+use crate::linalg::*;
+use crate::shader::*;
+
+pub const TILE_SIZE: usize = 64;
+
+pub fn draw_mesh_tiled<VS, FS>(
+    mesh: &Mesh,
+    transform: &Mat4x4f, camera: &Camera,
+    state: &RenderState,
+    vs: &VS, fs: &FS,
+    screen: &mut Screen)
+    where VS: VertexShader, VS::Output: Send + Sync, FS: FragmentShader<VS::Output> + Sync {
+    let tris = process_geometry(mesh, transform, camera, vs, screen.width, screen.height);
+    rasterize_tiled(screen, &tris, state, fs);
+}
+
+pub fn rasterize_tiled<V, FS>(screen: &mut Screen, tris: &[ScreenTriangle<V>], state: &RenderState, fs: &FS)
+    where V: Varyings + Send + Sync, FS: FragmentShader<V> + Sync {
+    let bins = bin_triangles(tris, screen.width, screen.height);
+
+    screen.tiles(TILE_SIZE).into_par_iter().zip(bins.into_par_iter())
+        .filter(|(_, bin)| !bin.is_empty())
+        .for_each(|(mut tile, bin)| {
+            for i in bin.iter() {
+                rasterize_triangle(&mut tile, &tris[*i], state, fs);
+            }
+        });
+}
+
+// For each tile, row-major, the indices of the triangles whose bounds overlap it
+fn bin_triangles<V: Varyings>(tris: &[ScreenTriangle<V>], width: usize, height: usize) -> Vec<Vec<usize>> {
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let tiles_y = height.div_ceil(TILE_SIZE);
+    let mut bins: Vec<Vec<usize>> = vec![Vec::new(); tiles_x * tiles_y];
+
+    for (i, tri) in tris.iter().enumerate() {
+        if tri.min.x >= tri.max.x || tri.min.y >= tri.max.y {
+            continue;
+        }
+
+        let tile_min_x = tri.min.x as usize / TILE_SIZE;
+        let tile_min_y = tri.min.y as usize / TILE_SIZE;
+        let tile_max_x = (tri.max.x as usize - 1) / TILE_SIZE;
+        let tile_max_y = (tri.max.y as usize - 1) / TILE_SIZE;
+
+        for tile_y in tile_min_y..=tile_max_y {
+            for tile_x in tile_min_x..=tile_max_x {
+                bins[tile_y * tiles_x + tile_x].push(i);
+            }
+        }
+    }
+
+    bins
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::light::*;
+    use crate::resources::*;
+    use crate::texture::*;
+
+    // A few overlapping, spinning cubes, some poking through the screen edges
+    fn draw_cubes<F>(screen: &mut Screen, mut draw: F)
+        where F: FnMut(&Mesh, &Mat4x4f, &Camera, &mut Screen) {
+        let mesh = create_cube();
+        let mut camera = Camera::perspective(80.0, 0.1, 1000.0, screen.height as f32 / screen.width as f32);
+        camera.set_position(&Vec3f::new(0.0, 0.0, -8.0));
+
+        for i in 0..6 {
+            let t = i as f32 * 0.7;
+            let obj_mat =
+                Mat4x4f::translation(f32::sin(t) * 2.5, f32::cos(t * 1.3) * 1.5, f32::sin(t * 0.4)) *
+                Mat4x4f::rotation_y(t * 2.0) *
+                Mat4x4f::rotation_x(t);
+
+            draw(&mesh, &obj_mat, &camera, screen);
+        }
+    }
+
+    #[test]
+    fn test_tiled_matches_single_threaded() {
+        let mut tex = load_texture(String::from("resources/checker.png")).unwrap();
+        tex.sampler.filter = Filter::Trilinear;
+
+        let lights = [
+            Light::Directional { direction: Vec3f::new(0.0, -0.5, 1.0), color: Vec3f::new(0.9, 0.9, 0.9) },
+            Light::Point { position: Vec3f::new(1.0, 1.0, -3.0), color: Vec3f::new(1.0, 0.5, 0.2), range: 8.0 },
+        ];
+        let vs = StandardVertexShader;
+        let fs = LitShader::new(&tex, &lights, &Vec3f::new(0.0, 0.0, -8.0));
+
+        // Deliberately not a multiple of the tile size
+        for &(width, height, multisample) in [(200, 150, Multisample::Off), (333, 257, Multisample::Off), (333, 257, Multisample::X4)].iter() {
+            let mut reference = Screen::new(width, height);
+            reference.set_multisample(multisample);
+            draw_cubes(&mut reference, |mesh, obj_mat, camera, screen| {
+                draw_mesh(mesh, obj_mat, camera, &RenderState::new(), &vs, &fs, screen);
+            });
+
+            let mut tiled = Screen::new(width, height);
+            tiled.set_multisample(multisample);
+            draw_cubes(&mut tiled, |mesh, obj_mat, camera, screen| {
+                draw_mesh_tiled(mesh, obj_mat, camera, &RenderState::new(), &vs, &fs, screen);
+            });
+
+            resolve_samples(&mut reference);
+            resolve_samples(&mut tiled);
+
+            assert!(reference.color.iter().any(|c| *c != 0));
+            assert!(reference.color == tiled.color);
+            assert!(reference.depth.iter().zip(tiled.depth.iter()).all(|(a, b)| a.to_bits() == b.to_bits()));
+            assert!(reference.sample_color == tiled.sample_color);
+        }
+    }
+
+    // A flat color with the color's alpha, lit just enough to tell the faces apart
+    struct TranslucentShader {
+        color: Color,
+    }
+
+    impl FragmentShader<StandardVaryings> for TranslucentShader {
+        fn fragment(&self, frag: &Fragment<StandardVaryings>) -> Option<Color> {
+            let light_dir = Vec3f::new(0.3, -0.5, 1.0).normalize();
+            let l_dot_n = f32::max(0.0, -Vec3f::dot(&frag.varyings.normal.normalize(), &light_dir));
+            let color = self.color.to_vec3() * (0.4 + 0.6 * l_dot_n);
+            Some(Color::from_vec3(&color).with_alpha(self.color.a))
+        }
+    }
+
+    // Translucent cubes poking through each other and through an opaque one
+    fn draw_translucent_cubes(order: &[usize], transparency: TransparencyMode, multisample: Multisample) -> Screen {
+        let mesh = create_cube();
+        let mut screen = Screen::new(160, 120);
+        screen.set_transparency(transparency);
+        screen.set_multisample(multisample);
+        let mut camera = Camera::perspective(50.0, 0.1, 100.0, 0.75);
+        camera.set_position(&Vec3f::new(1.0, 2.0, -9.0));
+        camera.look_at(&Vec3f::new(0.0, 0.0, 0.0), &Vec3f::new(0.0, 1.0, 0.0));
+
+        let cubes = [
+            (Mat4x4f::translation(-0.6, 0.0, 0.0) * Mat4x4f::rotation_y(0.5), Color::rgba(255, 60, 60, 140)),
+            (Mat4x4f::translation(0.6, 0.3, 0.2) * Mat4x4f::rotation_x(0.7), Color::rgba(60, 255, 60, 110)),
+            (Mat4x4f::translation(0.0, -0.4, -0.5) * Mat4x4f::rotation_z(0.4), Color::rgba(60, 60, 255, 170)),
+            (Mat4x4f::translation(0.4, 0.9, 0.8) * Mat4x4f::scale_uniform(0.6), Color::new(255, 255, 255)),
+        ];
+
+        let vs = StandardVertexShader;
+        for &i in order.iter() {
+            let (obj_mat, color) = cubes[i];
+            let state = if color.a == 255 { RenderState::new() } else { RenderState::transparent() };
+            draw_mesh_tiled(&mesh, &obj_mat, &camera, &state, &vs, &TranslucentShader { color: color }, &mut screen);
+        }
+
+        resolve_transparency(&mut screen);
+        resolve_samples(&mut screen);
+        screen
+    }
+
+    /*
+        Compares against a reference image. Set RAMJET_UPDATE_GOLDEN to write
+        out a new one, after checking that a change in output is intended.
+    */
+    #[test]
+    fn test_order_independent_transparency_golden() {
+        let golden_path = Path::new("resources/golden/oit_cubes.png");
+        let screen = draw_translucent_cubes(&[0, 1, 2, 3], TransparencyMode::OrderIndependent, Multisample::Off);
+
+        if std::env::var("RAMJET_UPDATE_GOLDEN").is_ok() {
+            save_png(&screen, golden_path).unwrap();
+        }
+
+        let golden = image::open(golden_path).unwrap().to_rgb().into_raw();
+        assert_eq!(golden.len(), screen.color.len());
+        assert!(golden.iter().zip(screen.color.iter()).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1));
+
+        // Draw order doesn't matter, opaque geometry included
+        for order in [[3, 2, 1, 0], [1, 3, 0, 2]].iter() {
+            assert!(draw_translucent_cubes(order, TransparencyMode::OrderIndependent, Multisample::Off).color == screen.color);
+        }
+
+        // Where blending in draw order does get it wrong
+        assert!(draw_translucent_cubes(&[3, 2, 1, 0], TransparencyMode::InDrawOrder, Multisample::Off).color != screen.color);
+
+        // Nor with multisampling, where the A-buffer keeps track of which samples each fragment covers
+        let multisampled = draw_translucent_cubes(&[0, 1, 2, 3], TransparencyMode::OrderIndependent, Multisample::X4);
+        assert!(draw_translucent_cubes(&[1, 3, 0, 2], TransparencyMode::OrderIndependent, Multisample::X4).color == multisampled.color);
+        assert!(multisampled.color != screen.color);
+
+        // It only makes a difference along edges
+        let same = multisampled.color.iter().zip(screen.color.iter()).filter(|(a, b)| (**a as i32 - **b as i32).abs() <= 1).count();
+        assert!(same > screen.color.len() * 9 / 10);
+    }
+}